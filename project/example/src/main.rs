@@ -4,7 +4,7 @@ use vfs_lib::*;
 
 fn main() -> Result<(), Error> {
     std::fs::remove_file("realfile.vfs").unwrap_or_default();
-    let vfs = Vfs::open("realfile.vfs");
+    let vfs = Vfs::create_new("realfile.vfs")?;
 
     let mut f1 = vfs.create("file1.txt")?;
     vfs.create_dir("first_dir")?;
@@ -16,12 +16,12 @@ fn main() -> Result<(), Error> {
     vfs.create("first_dir/second_dir/file😮.txt")?;
     vfs.create("first_dir/second_dir/file😆.txt")?;
 
-    vfs.print_tree();
+    vfs.print_tree()?;
 
     vfs.delete("first_dir/second_dir")?;
 
     println!();
-    vfs.print_tree();
+    vfs.print_tree()?;
 
     f1.write_all("We 💚 Rust".as_bytes())?;
     f2.write_all("Hello Wolrd! 🖐".as_bytes())?;
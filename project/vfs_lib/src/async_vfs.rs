@@ -0,0 +1,486 @@
+//! Async facade over [`Vfs`], for use inside async runtimes. Every call runs the blocking
+//! implementation on a separate thread pool, so a write that has to move a whole file never stalls
+//! the executor. Behaviour is otherwise exactly that of the sync API.
+
+#[cfg(test)]
+mod tests {
+    use futures_io::AsyncSeek;
+    use futures_lite::future::block_on;
+    use futures_lite::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{MemoryStorage, Storage};
+
+    fn memory_vfs() -> AsyncVfs {
+        AsyncVfs::from(Vfs::with_storage(MemoryStorage::new()).unwrap())
+    }
+
+    #[test]
+    fn test_async_read_write_seek() {
+        block_on(async {
+            let vfs = memory_vfs();
+            vfs.create_dir("dir").await.unwrap();
+            let mut file = vfs.create("dir/file").await.unwrap();
+            let contents: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+            file.write_all(&contents).await.unwrap();
+            file.flush().await.unwrap();
+            assert_eq!(file.metadata().await.unwrap().size, 10000);
+
+            let mut read_back = Vec::new();
+            let mut file = vfs.open_file("dir/file").await.unwrap();
+            file.read_to_end(&mut read_back).await.unwrap();
+            assert_eq!(read_back, contents);
+
+            assert_eq!(file.seek(SeekFrom::Start(5000)).await.unwrap(), 5000);
+            let mut buf = [0u8; 4];
+            file.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, contents[5000..5004]);
+
+            file.set_len(10).await.unwrap();
+            assert_eq!(file.metadata().await.unwrap().size, 10);
+            file.sync_all().await.unwrap();
+            file.close().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_async_vfs() {
+        block_on(async {
+            let vfs = memory_vfs();
+            vfs.create_dir("dir").await.unwrap();
+            vfs.create("dir/a").await.unwrap();
+            vfs.rename("dir/a", "dir/b").await.unwrap();
+            let names: Vec<String> = vfs
+                .read_dir("dir")
+                .await
+                .unwrap()
+                .iter()
+                .map(DirEntry::get_path)
+                .collect();
+            assert_eq!(names, vec!["dir/b".to_owned()]);
+
+            let result: Result<(), Error> = vfs
+                .transaction(|vfs| {
+                    vfs.create("dir/c")?;
+                    Err(Error::InvalidOptions)
+                })
+                .await;
+            assert_eq!(result, Err(Error::InvalidOptions));
+            assert_eq!(
+                vfs.open_file("dir/c").await.err(),
+                Some(Error::FileNotFound)
+            );
+
+            vfs.delete("dir").await.unwrap();
+            assert_eq!(
+                vfs.open_file("dir/b").await.err(),
+                Some(Error::FileNotFound)
+            );
+            vfs.sync().await.unwrap();
+
+            //the facade writes the same image as the sync api
+            let storage = MemoryStorage::from(vfs.to_bytes().await.unwrap());
+            let mut header = [0u8; 4];
+            storage.read_at(0, &mut header).unwrap();
+            assert_eq!(header, crate::MAGIC);
+        });
+    }
+
+    #[test]
+    fn test_dropped_calls() {
+        block_on(async {
+            let vfs = memory_vfs();
+            let mut file = vfs.create("file").await.unwrap();
+            let mut cx = Context::from_waker(std::task::Waker::noop());
+            //holding the lock keeps calls on the thread pool pending
+            let lock = || vfs.get_ref().internal.write();
+
+            //a write future that is dropped while its call is running still writes, and the next
+            //write doesn't take over its result
+            let first = vec![b'a'; 100000];
+            {
+                let _guard = lock();
+                assert!(Pin::new(&mut file).poll_write(&mut cx, &first).is_pending());
+            }
+            file.write_all(b"second").await.unwrap();
+            let mut contents = Vec::new();
+            let mut reader = vfs.open_file("file").await.unwrap();
+            reader.read_to_end(&mut contents).await.unwrap();
+            let mut expected = first.clone();
+            expected.extend_from_slice(b"second");
+            assert_eq!(contents, expected);
+
+            //bytes read for a dropped future are read again by the next one
+            reader.seek(SeekFrom::Start(99998)).await.unwrap();
+            let mut buf = [0u8; 5];
+            {
+                let _guard = lock();
+                assert!(Pin::new(&mut reader)
+                    .poll_read(&mut cx, &mut buf)
+                    .is_pending());
+            }
+            let mut buf = [0u8; 3];
+            reader.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"aas");
+
+            //a dropped seek still moves the cursor before the next one
+            {
+                let _guard = lock();
+                assert!(Pin::new(&mut reader)
+                    .poll_seek(&mut cx, SeekFrom::Start(0))
+                    .is_pending());
+            }
+            assert_eq!(reader.seek(SeekFrom::Current(2)).await.unwrap(), 2);
+        });
+    }
+
+    #[test]
+    fn test_async_file_from_sync() {
+        block_on(async {
+            let vfs = memory_vfs();
+            let file = vfs
+                .run(|vfs| vfs.options().write(true).create(true).open("file"))
+                .await
+                .unwrap();
+            let mut file = AsyncFile::from(file);
+            file.write_all(b"from sync").await.unwrap();
+
+            let mut file = vfs.open_file("file").await.unwrap();
+            let mut pinned = Pin::new(&mut file);
+            let position =
+                std::future::poll_fn(|cx| pinned.as_mut().poll_seek(cx, SeekFrom::End(-4)));
+            assert_eq!(position.await.unwrap(), 5);
+            let mut contents = String::new();
+            file.read_to_string(&mut contents).await.unwrap();
+            assert_eq!(contents, "sync");
+        });
+    }
+}
+
+use std::any::Any;
+use std::future::Future;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use blocking::{unblock, Task};
+use futures_io::{AsyncRead, AsyncSeek, AsyncWrite};
+
+use crate::{DirEntry, Error, File, LayoutReport, Metadata, Vfs, VfsStats};
+
+/// A [`Vfs`] whose calls don't block the executor. Cloning it is cheap, clones share the image.
+#[derive(Clone)]
+pub struct AsyncVfs {
+    vfs: Arc<Vfs>,
+}
+
+impl From<Vfs> for AsyncVfs {
+    fn from(vfs: Vfs) -> Self {
+        AsyncVfs { vfs: Arc::new(vfs) }
+    }
+}
+
+impl AsyncVfs {
+    /// See [`Vfs::open_existing`].
+    pub async fn open_existing(filename: &str) -> Result<AsyncVfs, Error> {
+        let filename = filename.to_owned();
+        let vfs = unblock(move || Vfs::open_existing(&filename)).await?;
+        Ok(AsyncVfs::from(vfs))
+    }
+
+    /// See [`Vfs::create_new`].
+    pub async fn create_new(filename: &str) -> Result<AsyncVfs, Error> {
+        let filename = filename.to_owned();
+        let vfs = unblock(move || Vfs::create_new(&filename)).await?;
+        Ok(AsyncVfs::from(vfs))
+    }
+
+    /// See [`Vfs::open_or_create`].
+    pub async fn open_or_create(filename: &str) -> Result<AsyncVfs, Error> {
+        let filename = filename.to_owned();
+        let vfs = unblock(move || Vfs::open_or_create(&filename)).await?;
+        Ok(AsyncVfs::from(vfs))
+    }
+
+    /// The sync api of the same image. Its calls block, keep them off the executor.
+    pub fn get_ref(&self) -> &Vfs {
+        &self.vfs
+    }
+
+    /// Runs `f` on the thread pool, for whatever the async api doesn't cover.
+    pub async fn run<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&Vfs) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let vfs = self.vfs.clone();
+        unblock(move || f(&vfs)).await
+    }
+
+    pub async fn create_dir(&self, path: &str) -> Result<(), Error> {
+        let path = path.to_owned();
+        self.run(move |vfs| vfs.create_dir(&path)).await
+    }
+
+    pub async fn create(&self, path: &str) -> Result<AsyncFile, Error> {
+        let path = path.to_owned();
+        let file = self.run(move |vfs| vfs.create(&path)).await?;
+        Ok(AsyncFile::from(file))
+    }
+
+    pub async fn open_file(&self, path: &str) -> Result<AsyncFile, Error> {
+        let path = path.to_owned();
+        let file = self.run(move |vfs| vfs.open_file(&path)).await?;
+        Ok(AsyncFile::from(file))
+    }
+
+    /// Lists a directory in one go, see [`Vfs::read_dir`].
+    pub async fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, Error> {
+        let path = path.to_owned();
+        self.run(move |vfs| vfs.read_dir(&path)?.collect()).await
+    }
+
+    /// See [`Vfs::transaction`].
+    pub async fn transaction<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&Vfs) -> Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: From<Error> + Send + 'static,
+    {
+        self.run(move |vfs| vfs.transaction(f)).await
+    }
+
+    pub async fn rename(&self, from: &str, to: &str) -> Result<(), Error> {
+        let (from, to) = (from.to_owned(), to.to_owned());
+        self.run(move |vfs| vfs.rename(&from, &to)).await
+    }
+
+    pub async fn rename_overwrite(&self, from: &str, to: &str) -> Result<(), Error> {
+        let (from, to) = (from.to_owned(), to.to_owned());
+        self.run(move |vfs| vfs.rename_overwrite(&from, &to)).await
+    }
+
+    pub async fn delete(&self, path: &str) -> Result<(), Error> {
+        let path = path.to_owned();
+        self.run(move |vfs| vfs.delete(&path)).await
+    }
+
+    pub async fn sync(&self) -> Result<(), Error> {
+        self.run(|vfs| vfs.sync()).await
+    }
+
+    pub async fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        self.run(|vfs| vfs.to_bytes()).await
+    }
+
+    pub async fn stats(&self) -> Result<VfsStats, Error> {
+        self.run(|vfs| vfs.stats()).await
+    }
+
+    pub async fn layout_report(&self) -> Result<LayoutReport, Error> {
+        self.run(|vfs| vfs.layout_report()).await
+    }
+
+    pub async fn defragment(&self) -> Result<(), Error> {
+        self.run(|vfs| vfs.defragment()).await
+    }
+
+    pub async fn compact(&self) -> Result<(), Error> {
+        self.run(|vfs| vfs.compact()).await
+    }
+}
+
+/// What a call on the thread pool hands back along with the file.
+enum Outcome {
+    Read(std::io::Result<Vec<u8>>),
+    Write(std::io::Result<usize>),
+    Flush(std::io::Result<()>),
+    Seek(std::io::Result<u64>),
+    Other(Box<dyn Any + Send>),
+}
+
+/// What a call on the thread pool was started with. A later poll only gets its outcome if it asks
+/// for the very same thing, otherwise the call was left behind by a dropped future.
+#[derive(PartialEq)]
+enum Call {
+    Read(usize),
+    Write(Vec<u8>),
+    Flush,
+    Seek(SeekFrom),
+    //never picked up again, a future that is dropped takes its result with it
+    Other,
+}
+
+enum State {
+    Idle(Option<File>),
+    //the file is away on the thread pool until the task is done
+    Busy(Task<(File, Outcome)>, Call),
+}
+
+/// A [`File`] implementing the futures io traits. Each read, write or seek is one call of the sync
+/// api on the thread pool, so a write that returns has been committed just like [`File::write`].
+///
+/// A call that is still pending has to be polled again with the same arguments. A different call
+/// waits for the pending one and drops its result, like a dropped future of `tokio::fs::File`.
+/// Writes and seeks that were left behind stay done, bytes read for nobody are read again.
+pub struct AsyncFile {
+    state: State,
+}
+
+impl From<File> for AsyncFile {
+    fn from(file: File) -> Self {
+        AsyncFile {
+            state: State::Idle(Some(file)),
+        }
+    }
+}
+
+impl AsyncFile {
+    /// Sends the file to the thread pool with `start`, or waits for the call that is already running.
+    /// A running call that was started with something other than `call` was left by a dropped
+    /// future. Its outcome is thrown away and ours runs after it.
+    fn poll_call<F>(
+        &mut self,
+        cx: &mut Context<'_>,
+        call: Call,
+        start: &mut Option<F>,
+    ) -> Poll<Outcome>
+    where
+        F: FnOnce(&mut File) -> Outcome + Send + 'static,
+    {
+        let mut call = Some(call);
+        loop {
+            match &mut self.state {
+                State::Idle(file) => {
+                    let mut file = file.take().unwrap();
+                    let f = start.take().unwrap();
+                    self.state = State::Busy(
+                        unblock(move || {
+                            let outcome = f(&mut file);
+                            (file, outcome)
+                        }),
+                        call.take().unwrap(),
+                    );
+                }
+                State::Busy(task, running) => {
+                    let is_ours = start.is_none()
+                        || (*running != Call::Other && call.as_ref() == Some(running));
+                    let (mut file, outcome) = ready!(Pin::new(task).poll(cx));
+                    if is_ours {
+                        self.state = State::Idle(Some(file));
+                        return Poll::Ready(outcome);
+                    }
+                    //nobody got these bytes, the next read starts where this one did
+                    if let Outcome::Read(Ok(data)) = &outcome {
+                        file.cursor -= data.len() as u64;
+                    }
+                    self.state = State::Idle(Some(file));
+                }
+            }
+        }
+    }
+
+    async fn call<T, F>(&mut self, f: F) -> T
+    where
+        F: FnOnce(&mut File) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let mut start = Some(move |file: &mut File| Outcome::Other(Box::new(f(file))));
+        let outcome = std::future::poll_fn(|cx| self.poll_call(cx, Call::Other, &mut start)).await;
+        match outcome {
+            Outcome::Other(value) => *value.downcast().unwrap(),
+            _ => unreachable!(),
+        }
+    }
+
+    pub async fn metadata(&mut self) -> Result<Metadata, Error> {
+        self.call(|file| file.metadata()).await
+    }
+
+    /// See [`File::set_len`].
+    pub async fn set_len(&mut self, size: u64) -> Result<(), Error> {
+        self.call(move |file| file.set_len(size)).await
+    }
+
+    /// See [`File::allocate`].
+    pub async fn allocate(&mut self, length: u64) -> Result<(), Error> {
+        self.call(move |file| file.allocate(length)).await
+    }
+
+    pub async fn sync_all(&mut self) -> Result<(), Error> {
+        self.call(|file| file.sync_all()).await
+    }
+
+    pub async fn sync_data(&mut self) -> Result<(), Error> {
+        self.call(|file| file.sync_data()).await
+    }
+}
+
+impl AsyncRead for AsyncFile {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let length = buf.len();
+        let mut start = Some(move |file: &mut File| {
+            let mut data = vec![0u8; length];
+            Outcome::Read(file.read(&mut data).map(|read| {
+                data.truncate(read);
+                data
+            }))
+        });
+        match ready!(self.get_mut().poll_call(cx, Call::Read(length), &mut start)) {
+            Outcome::Read(data) => {
+                let data = data?;
+                let read = data.len().min(buf.len());
+                buf[..read].copy_from_slice(&data[..read]);
+                Poll::Ready(Ok(read))
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl AsyncWrite for AsyncFile {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let data = buf.to_vec();
+        let call = Call::Write(data.clone());
+        let mut start = Some(move |file: &mut File| Outcome::Write(file.write(&data)));
+        match ready!(self.get_mut().poll_call(cx, call, &mut start)) {
+            Outcome::Write(result) => Poll::Ready(result),
+            _ => unreachable!(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let mut start = Some(|file: &mut File| Outcome::Flush(file.flush()));
+        match ready!(self.get_mut().poll_call(cx, Call::Flush, &mut start)) {
+            Outcome::Flush(result) => Poll::Ready(result),
+            _ => unreachable!(),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl AsyncSeek for AsyncFile {
+    fn poll_seek(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<std::io::Result<u64>> {
+        let mut start = Some(move |file: &mut File| Outcome::Seek(file.seek(pos)));
+        match ready!(self.get_mut().poll_call(cx, Call::Seek(pos), &mut start)) {
+            Outcome::Seek(result) => Poll::Ready(result),
+            _ => unreachable!(),
+        }
+    }
+}
@@ -5,11 +5,33 @@
 // tests, deletion of directories and files
 
 #[cfg(test)]
+#[allow(
+    clippy::bool_assert_comparison,
+    clippy::int_plus_one,
+    clippy::needless_borrow,
+    clippy::unnecessary_unwrap
+)]
 mod tests {
     use std::{fs::remove_file, str::from_utf8, thread::sleep};
 
     use super::*;
 
+    //removes the image before and after the test, even when the test fails
+    struct TestImage(&'static str);
+
+    impl TestImage {
+        fn new(path: &'static str) -> Self {
+            remove_file(path).unwrap_or_default();
+            Self(path)
+        }
+    }
+
+    impl Drop for TestImage {
+        fn drop(&mut self) {
+            remove_file(self.0).unwrap_or_default();
+        }
+    }
+
     // #[test]
     // fn it_works() {
     //     let result = add(2, 2);
@@ -71,8 +93,7 @@ mod tests {
 
     #[test]
     fn tree_structure() {
-        remove_file("test_tree.vfs").unwrap_or_default();
-        let vfs = Vfs::open("test_tree.vfs");
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
 
         vfs.create("file1.txt").unwrap();
         vfs.create("file2.txt").unwrap();
@@ -86,13 +107,12 @@ mod tests {
         vfs.create("first_dir/second_dir/file5.txt").unwrap();
         vfs.create("first_dir/second_dir/file6.txt").unwrap();
 
-        vfs.print_tree();
+        vfs.print_tree().unwrap();
     }
 
     #[test]
     fn test_create_file_name_size_err() {
-        remove_file("create_file_name_size_err.vfs").unwrap_or_default();
-        let vfs = Vfs::open("create_file_name_size_err.vfs");
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
 
         assert_eq!(vfs.create(&"f".repeat(FILE_NAME_SIZE)).is_ok(), true);
         assert_eq!(
@@ -103,8 +123,7 @@ mod tests {
 
     #[test]
     fn read_write_to_single_file() {
-        remove_file("test_read_write.vfs").unwrap_or_default();
-        let vfs = Vfs::open("test_read_write.vfs");
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
 
         let mut f1 = vfs.create("file1.txt").unwrap();
 
@@ -119,8 +138,7 @@ mod tests {
 
     #[test]
     fn read_write_to_multiple_files() {
-        remove_file("test_read_write_2.vfs").unwrap_or_default();
-        let vfs = Vfs::open("test_read_write_2.vfs");
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
 
         let mut f1 = vfs.create("file1.txt").unwrap();
         let mut f2 = vfs.create("file2.txt").unwrap();
@@ -146,8 +164,7 @@ mod tests {
 
     #[test]
     fn write_overflow_to_file() {
-        remove_file("test_write_overflow.vfs").unwrap_or_default();
-        let vfs = Vfs::open("test_write_overflow.vfs");
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
 
         let mut f1 = vfs.create("file1.txt").unwrap();
         let mut f2 = vfs.create("file2.txt").unwrap();
@@ -160,25 +177,29 @@ mod tests {
 
         let f1_metadata = f1.get_metadata();
         let f2_metadata = f2.get_metadata();
+        let internal = vfs.internal.read();
 
-        assert_eq!(f1_metadata.contents, 6);
+        //the first page of file1 stays where it was, only the new page is added
+        assert_eq!(
+            internal.get_extents(&f1_metadata).unwrap(),
+            vec![4..5, 8..9]
+        );
         assert_eq!(
             f1_metadata.size as usize,
             b"Hello World!".len() + DEFAULT_PAGE_SIZE as usize
         );
-        assert_eq!(f2_metadata.contents, 5);
+        assert_eq!(internal.get_extents(&f2_metadata).unwrap(), vec![6..7]);
         assert_eq!(f2_metadata.size as usize, b"Hello World! again".len());
     }
 
     #[test]
     fn test_file_table_overflow() {
-        remove_file("test_file_table_overflow.vfs").unwrap_or_default();
-        let vfs = Vfs::open("test_file_table_overflow.vfs");
-        let page_size = vfs.internal.borrow().page_size;
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        let page_size = vfs.internal.read().page_size;
         for i in 0..(page_size / FILE_STRUCT_SIZE as u64 + 1) {
             vfs.create(&format!("file{}.txt", i)).unwrap();
         }
-        let internal = vfs.internal.borrow();
+        let internal = vfs.internal.read();
 
         assert_eq!(internal.file_table_page, DEFAULT_FILE_TABLE_PAGE);
         assert_eq!(internal.file_table_size, 2);
@@ -186,9 +207,8 @@ mod tests {
 
     #[test]
     fn test_file_table_overflow_write_to_file() {
-        remove_file("test_big_table_write_file.vfs").unwrap_or_default();
-        let vfs = Vfs::open("test_big_table_write_file.vfs");
-        let page_size = vfs.internal.borrow().page_size;
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        let page_size = vfs.internal.read().page_size;
         for i in 0..(page_size / FILE_STRUCT_SIZE as u64 + 1) {
             vfs.create(&format!("file{}.txt", i)).unwrap();
         }
@@ -208,8 +228,7 @@ mod tests {
         let mut dir;
 
         {
-            remove_file("nonexistent_file_system.vfs").unwrap_or_default();
-            let vfs = Vfs::open("nonexistent_file_system.vfs");
+            let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
 
             f = vfs.create("file.txt").unwrap();
             vfs.create_dir("test_dir").unwrap();
@@ -237,8 +256,7 @@ mod tests {
 
     #[test]
     fn test_cannot_have_dulicate_names() {
-        remove_file("duplicate_names.vfs").unwrap_or_default();
-        let vfs = Vfs::open("duplicate_names.vfs");
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
 
         vfs.create("file1").unwrap();
         assert_eq!(vfs.create("file1").unwrap_err(), Error::NameAlreadyInUse);
@@ -254,8 +272,7 @@ mod tests {
 
     #[test]
     fn test_cannot_create_in_nonexistent_directory() {
-        remove_file("nonexistent_directory.vfs").unwrap_or_default();
-        let vfs = Vfs::open("nonexistent_directory.vfs");
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
 
         vfs.create_dir("dir1").unwrap();
         assert_eq!(
@@ -270,15 +287,15 @@ mod tests {
 
     #[test]
     fn test_cannot_create_files_when_full() {
-        remove_file("test_file_table_overflow_when_full.vfs").unwrap_or_default();
-        let vfs = Vfs::open("test_file_table_overflow_when_full.vfs");
-        let page_size = vfs.internal.borrow().page_size;
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        let page_size = vfs.internal.read().page_size;
         for i in 0..(page_size / FILE_STRUCT_SIZE as u64 - 1) {
             vfs.create(&format!("file{}.txt", i)).unwrap();
         }
 
         {
-            let internal = vfs.internal.borrow();
+            let mut internal = vfs.internal.write();
+            internal.max_pages = 8 * internal.page_size;
             internal
                 .allocate_page_range(0..(8 * internal.page_size), true)
                 .unwrap();
@@ -296,8 +313,7 @@ mod tests {
 
     #[test]
     fn write_overflow_to_file_when_full() {
-        remove_file("test_write_overflow_when_full.vfs").unwrap_or_default();
-        let vfs = Vfs::open("test_write_overflow_when_full.vfs");
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
 
         let mut f1 = vfs.create("file1.txt").unwrap();
         let mut f2 = vfs.create("file2.txt").unwrap();
@@ -306,7 +322,8 @@ mod tests {
         f2.write_all(b"Hello World! again").unwrap();
 
         {
-            let internal = vfs.internal.borrow();
+            let mut internal = vfs.internal.write();
+            internal.max_pages = 8 * internal.page_size;
             internal
                 .allocate_page_range(0..(8 * internal.page_size), true)
                 .unwrap();
@@ -322,10 +339,369 @@ mod tests {
         assert_eq!(f1.metadata().unwrap().size, b"Hello World!".len() as u64)
     }
 
+    #[test]
+    fn test_allocation_table_growth() {
+        let _image = TestImage::new("allocation_table_growth.vfs");
+        {
+            let vfs = Vfs::create_new("allocation_table_growth.vfs").unwrap();
+            let mut f = vfs.create("file.txt").unwrap();
+
+            {
+                let internal = vfs.internal.read();
+                internal
+                    .allocate_page_range(0..(8 * internal.page_size), true)
+                    .unwrap();
+            }
+
+            f.write_all(b"Hello World!").unwrap();
+            f.write_all(&"f".repeat(DEFAULT_PAGE_SIZE as usize).into_bytes())
+                .unwrap();
+
+            let internal = vfs.internal.read();
+            assert!(internal.allocation_table_size > 1);
+            assert_eq!(
+                internal
+                    .is_page_allocated(internal.allocation_table_page)
+                    .unwrap(),
+                true
+            );
+        }
+
+        let vfs = Vfs::open("allocation_table_growth.vfs").unwrap();
+        let mut f = vfs.open_file("file.txt").unwrap();
+        let mut bytes = Vec::new();
+        f.read_to_end(&mut bytes).unwrap();
+        assert_eq!(&bytes[..12], b"Hello World!");
+        assert_eq!(bytes.len(), 12 + DEFAULT_PAGE_SIZE as usize);
+
+        let internal = vfs.internal.read();
+        assert!(internal.allocation_table_size > 1);
+    }
+
+    #[test]
+    fn write_into_fragmented_free_space() {
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+
+        for i in 0..5 {
+            let mut f = vfs.create(&format!("file{}", i)).unwrap();
+            f.write_all(b"Hello World!").unwrap();
+        }
+        vfs.delete("file1").unwrap();
+        vfs.delete("file3").unwrap();
+        vfs.internal.write().max_pages = 14;
+
+        let data = "f".repeat(3 * DEFAULT_PAGE_SIZE as usize).into_bytes();
+        let mut f = vfs.create("big").unwrap();
+        f.write_all(&data).unwrap();
+
+        let metadata = f.get_metadata();
+        let extents = vfs.internal.read().get_extents(&metadata).unwrap();
+        assert_eq!(extents, vec![6..8, 10..11]);
+
+        f.seek(SeekFrom::Start(0)).unwrap();
+        let mut bytes = Vec::new();
+        f.read_to_end(&mut bytes).unwrap();
+        assert_eq!(bytes, data);
+
+        let mut f0 = vfs.open_file("file0").unwrap();
+        let mut text = String::new();
+        f0.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "Hello World!");
+    }
+
+    #[test]
+    fn test_create_with_options() {
+        let _image = TestImage::new("create_with_options.vfs");
+        {
+            let vfs = Vfs::create_with(
+                "create_with_options.vfs",
+                VfsOptions::new().page_size(512).initial_file_table_pages(4),
+            )
+            .unwrap();
+
+            for i in 0..10 {
+                vfs.create(&format!("file{}.txt", i)).unwrap();
+            }
+            let mut f = vfs.open_file("file7.txt").unwrap();
+            f.write_all(&"f".repeat(2000).into_bytes()).unwrap();
+
+            let internal = vfs.internal.read();
+            assert_eq!(internal.page_size, 512);
+            assert_eq!(internal.file_table_size, 4);
+        }
+
+        let vfs = Vfs::open("create_with_options.vfs").unwrap();
+        assert_eq!(vfs.internal.read().page_size, 512);
+
+        let mut f = vfs.open_file("file7.txt").unwrap();
+        let mut text = String::new();
+        f.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "f".repeat(2000));
+    }
+
+    #[test]
+    fn test_create_with_max_size() {
+        let _image = TestImage::new("create_with_max_size.vfs");
+        {
+            let vfs = Vfs::create_with(
+                "create_with_max_size.vfs",
+                VfsOptions::new().max_size(8 * DEFAULT_PAGE_SIZE),
+            )
+            .unwrap();
+            let mut f = vfs.create("file").unwrap();
+            f.write_all(&"f".repeat(3 * DEFAULT_PAGE_SIZE as usize).into_bytes())
+                .unwrap();
+        }
+
+        let vfs = Vfs::open("create_with_max_size.vfs").unwrap();
+        let mut f = vfs.open_file("file").unwrap();
+        f.seek(SeekFrom::End(0)).unwrap();
+        assert_eq!(
+            f.write_all(b"no more room").unwrap_err().kind(),
+            std::io::ErrorKind::OutOfMemory
+        );
+    }
+
+    #[test]
+    fn test_create_with_invalid_options() {
+        let _image = TestImage::new("invalid_options.vfs");
+        assert_eq!(
+            Vfs::create_with("invalid_options.vfs", VfsOptions::new().page_size(1000)).err(),
+            Some(Error::InvalidOptions)
+        );
+        assert_eq!(
+            Vfs::create_with("invalid_options.vfs", VfsOptions::new().page_size(256)).err(),
+            Some(Error::InvalidOptions)
+        );
+        assert_eq!(
+            Vfs::create_with("invalid_options.vfs", VfsOptions::new().page_size(1 << 40)).err(),
+            Some(Error::InvalidOptions)
+        );
+        assert_eq!(
+            Vfs::create_with(
+                "invalid_options.vfs",
+                VfsOptions::new().max_size(2 * DEFAULT_PAGE_SIZE)
+            )
+            .err(),
+            Some(Error::InvalidOptions)
+        );
+    }
+
+    #[test]
+    fn test_cannot_open_invalid_images() {
+        let _empty_image = TestImage::new("empty_image.vfs");
+        let _future_image = TestImage::new("future_image.vfs");
+        let _not_an_image = TestImage::new("not_an_image.vfs");
+        let _truncated_image = TestImage::new("truncated_image.vfs");
+        std::fs::write("not_an_image.vfs", b"\xFF\xD8\xFF\xE0 definitely a jpeg").unwrap();
+        assert_eq!(
+            Vfs::open("not_an_image.vfs").err(),
+            Some(Error::NotAVfsImage)
+        );
+
+        std::fs::write("empty_image.vfs", b"").unwrap();
+        assert_eq!(
+            Vfs::open("empty_image.vfs").err(),
+            Some(Error::NotAVfsImage)
+        );
+
+        Vfs::create_new("truncated_image.vfs").unwrap();
+        fs::File::options()
+            .write(true)
+            .open("truncated_image.vfs")
+            .unwrap()
+            .set_len(2 * DEFAULT_PAGE_SIZE)
+            .unwrap();
+        assert_eq!(
+            Vfs::open("truncated_image.vfs").err(),
+            Some(Error::NotAVfsImage)
+        );
+
+        Vfs::create_new("future_image.vfs").unwrap();
+        {
+            let mut file = fs::File::options()
+                .write(true)
+                .open("future_image.vfs")
+                .unwrap();
+            file.seek(SeekFrom::Start(MAGIC.len() as u64)).unwrap();
+            file.write_all(&(FORMAT_VERSION + 1).to_le_bytes()).unwrap();
+        }
+        assert_eq!(
+            Vfs::open("future_image.vfs").err(),
+            Some(Error::UnsupportedVersion(FORMAT_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn test_create_and_open_entry_points() {
+        let _entry_points = TestImage::new("entry_points.vfs");
+        let _entry_points_fresh = TestImage::new("entry_points_fresh.vfs");
+
+        assert_eq!(
+            Vfs::open_existing("entry_points.vfs").err(),
+            Some(Error::IO(std::io::ErrorKind::NotFound))
+        );
+        assert_eq!(
+            Vfs::open("entry_points.vfs").err(),
+            Some(Error::IO(std::io::ErrorKind::NotFound))
+        );
+
+        {
+            let vfs = Vfs::create_new("entry_points.vfs").unwrap();
+            vfs.create("file").unwrap();
+        }
+
+        assert_eq!(
+            Vfs::create_new("entry_points.vfs").err(),
+            Some(Error::IO(std::io::ErrorKind::AlreadyExists))
+        );
+        assert_eq!(
+            Vfs::create_with("entry_points.vfs", &VfsOptions::new()).err(),
+            Some(Error::IO(std::io::ErrorKind::AlreadyExists))
+        );
+
+        let vfs = Vfs::open_or_create("entry_points.vfs").unwrap();
+        assert_eq!(vfs.open_file("file").is_ok(), true);
+        drop(vfs);
+
+        let vfs = Vfs::open_existing("entry_points.vfs").unwrap();
+        assert_eq!(vfs.open_file("file").is_ok(), true);
+
+        let vfs = Vfs::open_or_create("entry_points_fresh.vfs").unwrap();
+        assert_eq!(vfs.open_file("file").unwrap_err(), Error::FileNotFound);
+    }
+
+    #[test]
+    fn test_open_options() {
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+
+        assert_eq!(
+            vfs.options().read(true).open("file").unwrap_err(),
+            Error::FileNotFound
+        );
+        assert_eq!(
+            vfs.options()
+                .read(true)
+                .create(true)
+                .open("file")
+                .unwrap_err(),
+            Error::InvalidOptions
+        );
+        assert_eq!(
+            vfs.options()
+                .append(true)
+                .truncate(true)
+                .open("file")
+                .unwrap_err(),
+            Error::InvalidOptions
+        );
+
+        let mut f = vfs.options().write(true).create(true).open("file").unwrap();
+        f.write_all(b"Hello World!").unwrap();
+        f.seek(SeekFrom::Start(0)).unwrap();
+        let mut text = String::new();
+        assert_eq!(
+            f.read_to_string(&mut text).unwrap_err().kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+
+        assert_eq!(
+            vfs.options()
+                .write(true)
+                .create_new(true)
+                .open("file")
+                .unwrap_err(),
+            Error::NameAlreadyInUse
+        );
+
+        let mut f = vfs.options().read(true).open("file").unwrap();
+        assert_eq!(
+            f.write_all(b"nope").unwrap_err().kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+        f.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "Hello World!");
+
+        let mut f = vfs.options().read(true).append(true).open("file").unwrap();
+        f.write_all(b" again").unwrap();
+        f.seek(SeekFrom::Start(0)).unwrap();
+        f.write_all(b" and again").unwrap();
+        f.seek(SeekFrom::Start(0)).unwrap();
+        text.clear();
+        f.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "Hello World! again and again");
+
+        let f = vfs
+            .options()
+            .write(true)
+            .truncate(true)
+            .open("file")
+            .unwrap();
+        let metadata = f.get_metadata();
+        assert_eq!(metadata.size, 0);
+        assert_eq!(metadata.contents, 0);
+        assert_eq!(vfs.internal.read().get_number_of_pages().unwrap(), 4);
+
+        //whoever comes second opens the file the first one created
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    vfs.options()
+                        .write(true)
+                        .create(true)
+                        .open("shared")
+                        .unwrap()
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_set_len() {
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        let mut f = vfs.create("log.txt").unwrap();
+
+        f.write_all(&"a".repeat(3 * DEFAULT_PAGE_SIZE as usize).into_bytes())
+            .unwrap();
+        assert_eq!(vfs.internal.read().get_number_of_pages().unwrap(), 8);
+
+        f.set_len(10).unwrap();
+        assert_eq!(f.metadata().unwrap().size, 10);
+        {
+            let internal = vfs.internal.read();
+            assert_eq!(internal.is_page_allocated(4).unwrap(), true);
+            assert_eq!(internal.is_page_allocated(5).unwrap(), false);
+            assert_eq!(internal.is_page_allocated(6).unwrap(), false);
+            assert_eq!(internal.is_page_allocated(7).unwrap(), true);
+        }
+
+        f.set_len(20).unwrap();
+        f.seek(SeekFrom::Start(0)).unwrap();
+        let mut bytes = Vec::new();
+        f.read_to_end(&mut bytes).unwrap();
+        assert_eq!(bytes, [&[b'a'; 10][..], &[0u8; 10][..]].concat());
+
+        f.set_len(DEFAULT_PAGE_SIZE + 5).unwrap();
+        f.seek(SeekFrom::Start(0)).unwrap();
+        bytes.clear();
+        f.read_to_end(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), DEFAULT_PAGE_SIZE as usize + 5);
+        assert!(bytes[10..].iter().all(|&byte| byte == 0));
+
+        f.set_len(0).unwrap();
+        assert_eq!(f.get_metadata().contents, 0);
+        assert_eq!(vfs.internal.read().get_number_of_pages().unwrap(), 4);
+
+        let f = vfs.options().read(true).open("log.txt").unwrap();
+        assert_eq!(
+            f.set_len(5).unwrap_err(),
+            Error::IO(std::io::ErrorKind::PermissionDenied)
+        );
+    }
+
     #[test]
     fn test_cannot_open_dir_as_file() {
-        remove_file("dir_file_confusion.vfs").unwrap_or_default();
-        let vfs = Vfs::open("dir_file_confusion.vfs");
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
 
         vfs.create("file").unwrap();
         vfs.create_dir("dir").unwrap();
@@ -336,9 +712,9 @@ mod tests {
 
     #[test]
     fn test_data_persistency() {
+        let _image = TestImage::new("data_persistency.vfs");
         {
-            remove_file("data_persistency.vfs").unwrap_or_default();
-            let vfs = Vfs::open("data_persistency.vfs");
+            let vfs = Vfs::create_new("data_persistency.vfs").unwrap();
 
             vfs.create_dir("dir1").unwrap();
             vfs.create_dir("dir1/dir2").unwrap();
@@ -346,7 +722,7 @@ mod tests {
             f.write_all(b"Hello World!").unwrap();
         }
 
-        let vfs = Vfs::open("data_persistency.vfs");
+        let vfs = Vfs::open("data_persistency.vfs").unwrap();
         let mut f = vfs.open_file("dir1/dir2/file").unwrap();
         let mut text = String::new();
         f.read_to_string(&mut text).unwrap();
@@ -355,8 +731,7 @@ mod tests {
 
     #[test]
     fn test_last_write_time() {
-        remove_file("last_write_time.vfs").unwrap_or_default();
-        let vfs = Vfs::open("last_write_time.vfs");
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
         let mut f = vfs.create("file1").unwrap();
         sleep(std::time::Duration::new(1, 0));
         f.write_all(b"lorem ipsum").unwrap();
@@ -369,8 +744,7 @@ mod tests {
 
     #[test]
     fn test_cannot_open_nonexistent_file() {
-        remove_file("open_nonexistent_file.vfs").unwrap_or_default();
-        let vfs = Vfs::open("open_nonexistent_file.vfs");
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
         vfs.create("file1").unwrap();
         vfs.create_dir("dir1").unwrap();
 
@@ -380,8 +754,7 @@ mod tests {
 
     #[test]
     fn test_delete_file_structure() {
-        remove_file("open_deleted_file.vfs").unwrap_or_default();
-        let vfs = Vfs::open("open_deleted_file.vfs");
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
         vfs.create("file1").unwrap();
         vfs.create_dir("dir1").unwrap();
 
@@ -424,8 +797,7 @@ mod tests {
 
     #[test]
     fn test_delete_file_contents() {
-        remove_file("delete_file_contents.vfs").unwrap_or_default();
-        let vfs = Vfs::open("delete_file_contents.vfs");
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
         {
             let mut f = vfs.create("file.txt").unwrap();
             f.write_all(
@@ -436,7 +808,7 @@ mod tests {
         }
 
         {
-            let internal = vfs.internal.borrow_mut();
+            let internal = vfs.internal.write();
             assert_eq!(internal.is_page_allocated(4).unwrap(), true);
             assert_eq!(internal.is_page_allocated(5).unwrap(), true);
             assert_eq!(internal.is_page_allocated(6).unwrap(), true);
@@ -446,7 +818,7 @@ mod tests {
         vfs.delete("file.txt").unwrap();
 
         {
-            let internal = vfs.internal.borrow_mut();
+            let internal = vfs.internal.write();
             assert_eq!(internal.is_page_allocated(4).unwrap(), false);
             assert_eq!(internal.is_page_allocated(5).unwrap(), false);
             assert_eq!(internal.is_page_allocated(6).unwrap(), false);
@@ -455,108 +827,4023 @@ mod tests {
     }
 
     #[test]
-    fn test_read_dir_deleted_files() {
-        remove_file("test_read_dir_deleted_files.vfs").unwrap_or_default();
-        let vfs = Vfs::open("test_read_dir_deleted_files.vfs");
-        vfs.create_dir("dir").unwrap();
-        vfs.create("dir/file1").unwrap();
-        vfs.create("dir/file2").unwrap();
-        vfs.create("dir/file3").unwrap();
-
-        let dir = vfs.read_dir("dir").unwrap();
-
-        vfs.delete("dir/file2").unwrap();
+    fn test_rename() {
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        vfs.create_dir("dir1").unwrap();
+        vfs.create_dir("dir1/sub").unwrap();
+        vfs.create_dir("dir2").unwrap();
+        vfs.create("dir1/first").unwrap();
+        vfs.create("dir1/second").unwrap();
+        vfs.create("dir2/other").unwrap();
 
-        let mut found1 = false;
-        let mut found2 = false;
-        let mut found3 = false;
-        for entry in dir {
-            if entry.is_ok() {
-                let entry = entry.unwrap();
-                if entry.get_path() == "dir/file1" {
-                    found1 = true;
-                }
+        let mut f = vfs.create("dir1/file.txt").unwrap();
+        f.write_all(b"moved contents").unwrap();
+        let creation_time = f.metadata().unwrap().creation_time;
 
-                if entry.get_path() == "dir/file2" {
-                    found2 = true;
-                }
+        vfs.rename("dir1/file.txt", "dir2/renamed.txt").unwrap();
+        assert_eq!(
+            vfs.open_file("dir1/file.txt").unwrap_err(),
+            Error::FileNotFound
+        );
+        let mut f = vfs.open_file("dir2/renamed.txt").unwrap();
+        let mut contents = String::new();
+        f.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "moved contents");
+        assert_eq!(f.metadata().unwrap().creation_time, creation_time);
+
+        vfs.rename("dir1/first", "dir1/last").unwrap();
+        let names: Vec<String> = vfs
+            .read_dir("dir1")
+            .unwrap()
+            .map(|entry| entry.unwrap().get_path())
+            .collect();
+        assert_eq!(names, ["dir1/sub", "dir1/second", "dir1/last"]);
 
-                if entry.get_path() == "dir/file3" {
-                    found3 = true;
-                }
-            } else {
-                assert_eq!(entry.unwrap_err(), Error::FileNotFound);
-            }
-        }
+        assert_eq!(
+            vfs.rename("dir1", "dir1/sub/dir1").unwrap_err(),
+            Error::InvalidRename
+        );
+        assert_eq!(
+            vfs.rename("dir1", "dir1/dir1").unwrap_err(),
+            Error::InvalidRename
+        );
+        assert_eq!(
+            vfs.rename("dir1/second", "dir2/other").unwrap_err(),
+            Error::NameAlreadyInUse
+        );
+        assert_eq!(
+            vfs.rename("dir1/missing", "dir2/missing").unwrap_err(),
+            Error::FileNotFound
+        );
 
-        assert_eq!(found1, true);
-        assert_eq!(found2, false);
-        assert_eq!(found3, true);
+        vfs.rename("dir1", "dir2/dir1").unwrap();
+        assert_eq!(vfs.open_file("dir2/dir1/second").is_ok(), true);
+        assert_eq!(vfs.read_dir("dir2/dir1/sub").is_ok(), true);
+        assert_eq!(vfs.read_dir("dir1").unwrap_err(), Error::DirectoryNotFound);
     }
 
     #[test]
-    fn example() -> Result<(), Error> {
-        remove_file("example.vfs").unwrap_or_default();
-        let vfs = Vfs::open("example.vfs");
-
-        vfs.create_dir("rs")?;
-        {
-            let mut f1 = vfs.create("rs/abc.txt")?;
-            let mut f2 = vfs.create("rs/def.txt")?;
+    fn test_rename_overwrite() {
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        vfs.create_dir("dir").unwrap();
+        vfs.create("dir/child").unwrap();
+        vfs.create_dir("empty").unwrap();
 
-            f1.write_all(b"hello")?;
-            f2.write_all(b"world")?;
-        }
+        vfs.create("new").unwrap().write_all(b"new").unwrap();
+        vfs.create("old")
+            .unwrap()
+            .write_all(&[b'o'; DEFAULT_PAGE_SIZE as usize])
+            .unwrap();
+        assert_eq!(vfs.internal.read().is_page_allocated(6).unwrap(), true);
 
-        let mut data = String::new();
-        for entry in vfs.read_dir("rs")? {
-            let entry = entry?;
-            data.clear();
+        assert_eq!(
+            vfs.rename_overwrite("new", "dir").unwrap_err(),
+            Error::InvalidRename
+        );
+        assert_eq!(
+            vfs.rename_overwrite("empty", "dir").unwrap_err(),
+            Error::DirectoryNotEmpty
+        );
 
-            let mut file = vfs.open_entry(entry)?;
-            file.read_to_string(&mut data)?;
+        vfs.rename_overwrite("new", "old").unwrap();
+        assert_eq!(vfs.internal.read().is_page_allocated(6).unwrap(), false);
+        assert_eq!(vfs.open_file("new").unwrap_err(), Error::FileNotFound);
+        let mut contents = String::new();
+        vfs.open_file("old")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "new");
 
-            print!("{}", data);
-        }
-        println!();
-        Ok(())
+        vfs.rename_overwrite("dir", "empty").unwrap();
+        assert_eq!(vfs.open_file("empty/child").is_ok(), true);
+        assert_eq!(vfs.read_dir("dir").unwrap_err(), Error::DirectoryNotFound);
     }
-}
 
-use std::cell::RefCell;
-use std::fs::{self};
-use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    #[test]
+    fn test_transaction_rollback() {
+        let _image = TestImage::new("transaction_rollback.vfs");
+        let vfs = Vfs::create_new("transaction_rollback.vfs").unwrap();
+        vfs.create("kept").unwrap().write_all(b"kept").unwrap();
+        let length = fs::metadata("transaction_rollback.vfs").unwrap().len();
+
+        let result: Result<(), Error> = vfs.transaction(|tx| {
+            tx.create_dir("dir")?;
+            tx.create("dir/file")?
+                .write_all(&[b'n'; 3 * DEFAULT_PAGE_SIZE as usize])?;
+            tx.delete("kept")?;
+            assert_eq!(tx.open_file("kept").unwrap_err(), Error::FileNotFound);
+            assert_eq!(tx.open_file("dir/file")?.metadata()?.size, 12288);
+            Err(Error::FileNotFound)
+        });
+        assert_eq!(result.unwrap_err(), Error::FileNotFound);
+
+        assert_eq!(vfs.read_dir("dir").unwrap_err(), Error::DirectoryNotFound);
+        let mut contents = String::new();
+        vfs.open_file("kept")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "kept");
+        assert_eq!(
+            fs::metadata("transaction_rollback.vfs").unwrap().len(),
+            length
+        );
+        assert_eq!(vfs.internal.read().get_number_of_pages().unwrap(), 6);
+    }
+
+    #[test]
+    fn test_transaction_panic() {
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        vfs.create("kept").unwrap();
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            vfs.transaction(|tx| -> Result<(), Error> {
+                tx.delete("kept")?;
+                //dies while changing the image, which poisons the lock
+                let _internal = tx.internal.write();
+                panic!("halfway");
+            })
+        }));
+        assert!(result.is_err());
+
+        vfs.open_file("kept").unwrap();
+        vfs.create("new").unwrap();
+        check_allocation(&vfs);
+    }
+
+    #[test]
+    fn test_recover_from_panic() {
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        vfs.create("file").unwrap().write_all(b"kept").unwrap();
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _internal = vfs.internal.write();
+            panic!("halfway");
+        }));
+        assert!(result.is_err());
+
+        let mut contents = String::new();
+        vfs.open_file("file")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "kept");
+        vfs.create("g").unwrap();
+        check_allocation(&vfs);
+    }
+
+    #[test]
+    fn test_transaction_commit() {
+        let _image = TestImage::new("transaction_commit.vfs");
+        {
+            let vfs = Vfs::create_new("transaction_commit.vfs").unwrap();
+            vfs.create("old").unwrap().write_all(b"old").unwrap();
+
+            let size = vfs
+                .transaction(|tx| {
+                    tx.create_dir("dir")?;
+                    let mut f = tx.create("dir/file")?;
+                    f.write_all(b"first ")?;
+                    f.write_all(b"second")?;
+                    tx.rename("old", "dir/old")?;
+                    tx.transaction(|inner| inner.create("dir/inner").map(|_| ()))?;
+                    Ok::<u64, Error>(f.metadata()?.size)
+                })
+                .unwrap();
+            assert_eq!(size, 12);
+        }
+
+        let vfs = Vfs::open("transaction_commit.vfs").unwrap();
+        let mut contents = String::new();
+        vfs.open_file("dir/file")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "first second");
+        assert_eq!(vfs.open_file("dir/old").is_ok(), true);
+        assert_eq!(vfs.open_file("dir/inner").is_ok(), true);
+        assert_eq!(vfs.open_file("old").unwrap_err(), Error::FileNotFound);
+    }
+
+    #[test]
+    fn test_transaction_replayed_after_crash() {
+        let _image = TestImage::new("transaction_crash.vfs");
+        {
+            let vfs = Vfs::create_new("transaction_crash.vfs").unwrap();
+            vfs.internal.read().begin_transaction().unwrap();
+            vfs.create_dir("dir").unwrap();
+            vfs.create("dir/file")
+                .unwrap()
+                .write_all(&[b'c'; 2 * DEFAULT_PAGE_SIZE as usize])
+                .unwrap();
+
+            //crash right after the journal was written, before it got applied
+            assert_eq!(vfs.internal.write().stage_transaction().unwrap(), true);
+            assert_eq!(
+                vfs.internal
+                    .read()
+                    .get_file_struct_by_path("dir")
+                    .unwrap_err(),
+                Error::FileNotFound
+            );
+        }
+
+        let vfs = Vfs::open("transaction_crash.vfs").unwrap();
+        let mut bytes = Vec::new();
+        vfs.open_file("dir/file")
+            .unwrap()
+            .read_to_end(&mut bytes)
+            .unwrap();
+        assert_eq!(bytes, [b'c'; 2 * DEFAULT_PAGE_SIZE as usize]);
+        assert_eq!(
+            fs::metadata("transaction_crash.vfs").unwrap().len(),
+            vfs.internal.read().get_number_of_pages().unwrap() * DEFAULT_PAGE_SIZE
+        );
+    }
+
+    #[test]
+    fn test_delete_big_tree() {
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        vfs.create_dir("big").unwrap();
+        vfs.create_dir("big/sub").unwrap();
+        for i in 0..300 {
+            vfs.create(&format!("big/sub/file{}", i))
+                .unwrap()
+                .write_all(b"contents")
+                .unwrap();
+        }
+        vfs.create("kept").unwrap();
+
+        vfs.delete("big").unwrap();
+
+        assert_eq!(vfs.read_dir("big").unwrap_err(), Error::DirectoryNotFound);
+        assert_eq!(
+            vfs.open_file("big/sub/file150").unwrap_err(),
+            Error::FileNotFound
+        );
+        assert_eq!(vfs.open_file("kept").is_ok(), true);
+        let internal = vfs.internal.read();
+        let file_table_end = internal.file_table_page + internal.file_table_size;
+        assert_eq!(internal.get_number_of_pages().unwrap(), file_table_end);
+    }
+
+    #[test]
+    fn test_journal_spanning_pages() {
+        let _image = TestImage::new("journal_pages.vfs");
+        {
+            let vfs = Vfs::create_new("journal_pages.vfs").unwrap();
+            for i in 0..300 {
+                vfs.create(&format!("file{}", i)).unwrap();
+            }
+
+            //crash right after the journal was written
+            let internal = vfs.internal.read();
+            let modifications = (0..300)
+                .map(|i| {
+                    let (index, mut file) = internal
+                        .get_file_struct_by_path(&format!("file{}", i))
+                        .unwrap();
+                    file.name = format!("renamed{}", i);
+                    Modification::FileTable(index, file)
+                })
+                .collect();
+            internal.schedule_commit(modifications).unwrap();
+        }
+
+        let vfs = Vfs::open("journal_pages.vfs").unwrap();
+        assert_eq!(vfs.journal_recovery(), JournalRecovery::Replayed);
+        for i in 0..300 {
+            assert_eq!(vfs.open_file(&format!("renamed{}", i)).is_ok(), true);
+        }
+        assert_eq!(
+            fs::metadata("journal_pages.vfs").unwrap().len(),
+            vfs.internal.read().get_number_of_pages().unwrap() * DEFAULT_PAGE_SIZE
+        );
+    }
+
+    #[test]
+    fn test_incomplete_journal_is_discarded() {
+        let _image = TestImage::new("incomplete_journal.vfs");
+        {
+            let vfs = Vfs::create_new("incomplete_journal.vfs").unwrap();
+            vfs.create("file").unwrap();
+
+            let internal = vfs.internal.read();
+            let (index, mut file) = internal.get_file_struct_by_path("file").unwrap();
+            file.name = "renamed".to_owned();
+            internal
+                .schedule_commit(vec![Modification::FileTable(index, file)])
+                .unwrap();
+        }
+        {
+            let mut file = fs::File::options()
+                .write(true)
+                .open("incomplete_journal.vfs")
+                .unwrap();
+            file.seek(SeekFrom::Start(
+                DEFAULT_SYSTEM_COMMIT_PAGE * DEFAULT_PAGE_SIZE + JOURNAL_HEADER_SIZE + 20,
+            ))
+            .unwrap();
+            file.write_all(&[0xFF]).unwrap();
+        }
+
+        let vfs = Vfs::open("incomplete_journal.vfs").unwrap();
+        assert_eq!(vfs.journal_recovery(), JournalRecovery::Discarded);
+        assert_eq!(vfs.open_file("file").is_ok(), true);
+        assert_eq!(vfs.open_file("renamed").unwrap_err(), Error::FileNotFound);
+        assert_eq!(
+            vfs.internal.read().read_journal().unwrap().1,
+            Some(Vec::new())
+        );
+
+        drop(vfs);
+        let vfs = Vfs::open("incomplete_journal.vfs").unwrap();
+        assert_eq!(vfs.journal_recovery(), JournalRecovery::Clean);
+    }
+
+    #[test]
+    fn test_stale_journal_records_are_discarded() {
+        let _image = TestImage::new("stale_journal.vfs");
+        {
+            let vfs = Vfs::create_new("stale_journal.vfs").unwrap();
+            vfs.create("file").unwrap();
+
+            let internal = vfs.internal.read();
+            let (index, mut file) = internal.get_file_struct_by_path("file").unwrap();
+            file.name = "renamed".to_owned();
+            let record: Vec<u8> = Modification::FileTable(index, file.clone()).into();
+            let length = record.len() as u64 + 8;
+            internal
+                .schedule_commit(vec![Modification::FileTable(index, file)])
+                .unwrap();
+
+            //the next commit dies after its header, on top of the records of the previous one
+            internal.journal_sequence.fetch_add(1, Ordering::Relaxed);
+            internal.write_journal_header(1, length, 0).unwrap();
+        }
+
+        let vfs = Vfs::open("stale_journal.vfs").unwrap();
+        assert_eq!(vfs.journal_recovery(), JournalRecovery::Discarded);
+        assert_eq!(vfs.open_file("file").is_ok(), true);
+    }
+
+    #[test]
+    fn test_torn_journal_header_is_discarded() {
+        let _image = TestImage::new("torn_journal_header.vfs");
+        {
+            let vfs = Vfs::create_new("torn_journal_header.vfs").unwrap();
+            vfs.create("file").unwrap();
+
+            let internal = vfs.internal.read();
+            let (index, mut file) = internal.get_file_struct_by_path("file").unwrap();
+            file.name = "renamed".to_owned();
+            internal
+                .schedule_commit(vec![Modification::FileTable(index, file)])
+                .unwrap();
+        }
+        {
+            let mut file = fs::File::options()
+                .write(true)
+                .open("torn_journal_header.vfs")
+                .unwrap();
+            file.seek(SeekFrom::Start(
+                DEFAULT_SYSTEM_COMMIT_PAGE * DEFAULT_PAGE_SIZE + 24,
+            ))
+            .unwrap();
+            file.write_all(&[0; 16]).unwrap();
+        }
+
+        let vfs = Vfs::open("torn_journal_header.vfs").unwrap();
+        assert_eq!(vfs.journal_recovery(), JournalRecovery::Discarded);
+        assert_eq!(vfs.open_file("file").is_ok(), true);
+    }
+
+    #[test]
+    fn test_durability() {
+        let _image = TestImage::new("durability.vfs");
+        for durability in [Durability::None, Durability::MetadataOnly, Durability::Full] {
+            remove_file("durability.vfs").unwrap_or_default();
+            {
+                let vfs =
+                    Vfs::create_with("durability.vfs", VfsOptions::new().durability(durability))
+                        .unwrap();
+                assert_eq!(vfs.internal.read().durability, durability);
+                let mut f = vfs.create("file").unwrap();
+                f.write_all(b"synced").unwrap();
+                f.sync_data().unwrap();
+                f.sync_all().unwrap();
+                vfs.transaction(|tx| tx.create("in_transaction").map(|_| ()))
+                    .unwrap();
+                vfs.sync().unwrap();
+            }
+
+            let vfs = Vfs::open_with("durability.vfs", durability).unwrap();
+            assert_eq!(vfs.internal.read().durability, durability);
+            let mut contents = String::new();
+            vfs.open_file("file")
+                .unwrap()
+                .read_to_string(&mut contents)
+                .unwrap();
+            assert_eq!(contents, "synced");
+            assert_eq!(vfs.open_file("in_transaction").is_ok(), true);
+        }
+        assert_eq!(
+            Vfs::open("durability.vfs")
+                .unwrap()
+                .internal
+                .read()
+                .durability,
+            Durability::MetadataOnly
+        );
+    }
+
+    /// Storage that only keeps writes for sure once they are synced, and that can pretend the power
+    /// went out after a number of writes. From then on nothing it is given sticks anymore.
+    #[derive(Clone, Default)]
+    struct FaultyStorage {
+        state: Arc<Mutex<FaultyState>>,
+    }
+
+    #[derive(Default)]
+    struct FaultyState {
+        current: Vec<u8>,
+        durable: Vec<u8>,
+        //writes since the last sync, any of them may or may not have reached the disk
+        unsynced: Vec<UnsyncedWrite>,
+        writes: usize,
+        syncs: usize,
+        crash_at: Option<usize>,
+        //writes fail while this is set, like on a full disk
+        fail_writes: bool,
+    }
+
+    enum UnsyncedWrite {
+        Write(u64, Vec<u8>),
+        SetLen(u64),
+    }
+
+    impl FaultyStorage {
+        fn with_image(image: Vec<u8>) -> FaultyStorage {
+            let storage = FaultyStorage::default();
+            storage.state.lock().unwrap().current = image.clone();
+            storage.state.lock().unwrap().durable = image;
+            storage
+        }
+
+        fn crash_after(&self, writes: usize) {
+            let mut state = self.state.lock().unwrap();
+            state.crash_at = Some(state.writes + writes);
+        }
+
+        fn record_write(&self, write: UnsyncedWrite) {
+            let mut state = self.state.lock().unwrap();
+            if !state.has_crashed() {
+                state.unsynced.push(write);
+            }
+            state.writes += 1;
+        }
+
+        /// What the disk may hold after the crash. The unsynced writes whose bytes made it to disk
+        /// are any of the last ones, the disk is free to write them in whatever order it likes.
+        /// Changes to the length are metadata the file system keeps in order, those all stick.
+        /// A process that crashes without a power loss has all of its writes.
+        fn crash_images(&self, power_loss: bool) -> Vec<Vec<u8>> {
+            let state = self.state.lock().unwrap();
+            let last_kept = if power_loss { state.unsynced.len() } else { 0 };
+            (0..=last_kept)
+                .map(|first| {
+                    let mut image = state.durable.clone();
+                    for (i, write) in state.unsynced.iter().enumerate() {
+                        match write {
+                            UnsyncedWrite::Write(offset, bytes) => {
+                                let end = *offset as usize + bytes.len();
+                                if image.len() < end {
+                                    image.resize(end, 0);
+                                }
+                                if i >= first {
+                                    image[(*offset as usize)..end].copy_from_slice(bytes);
+                                }
+                            }
+                            UnsyncedWrite::SetLen(length) => image.resize(*length as usize, 0),
+                        }
+                    }
+                    image
+                })
+                .collect()
+        }
+    }
+
+    impl FaultyState {
+        //the power goes out right after the last write it gets, before anything else is synced
+        fn has_crashed(&self) -> bool {
+            self.crash_at
+                .is_some_and(|crash_at| self.writes >= crash_at)
+        }
+    }
+
+    impl Storage for FaultyStorage {
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+            let state = self.state.lock().unwrap();
+            let bytes = state
+                .current
+                .get((offset as usize)..(offset as usize + buf.len()))
+                .ok_or(std::io::ErrorKind::UnexpectedEof)?;
+            buf.copy_from_slice(bytes);
+            Ok(())
+        }
+
+        fn write_at(&mut self, offset: u64, buf: &[u8]) -> std::io::Result<()> {
+            if self.state.lock().unwrap().fail_writes {
+                return Err(std::io::ErrorKind::StorageFull.into());
+            }
+            self.record_write(UnsyncedWrite::Write(offset, buf.to_vec()));
+            let mut state = self.state.lock().unwrap();
+            let end = offset as usize + buf.len();
+            if state.current.len() < end {
+                state.current.resize(end, 0);
+            }
+            state.current[(offset as usize)..end].copy_from_slice(buf);
+            Ok(())
+        }
+
+        fn len(&self) -> std::io::Result<u64> {
+            Ok(self.state.lock().unwrap().current.len() as u64)
+        }
+
+        fn set_len(&mut self, length: u64) -> std::io::Result<()> {
+            if self.state.lock().unwrap().fail_writes {
+                return Err(std::io::ErrorKind::StorageFull.into());
+            }
+            self.record_write(UnsyncedWrite::SetLen(length));
+            self.state
+                .lock()
+                .unwrap()
+                .current
+                .resize(length as usize, 0);
+            Ok(())
+        }
+
+        //flush keeps the default, like on a real file it hands writes on but doesn't make them stick
+        fn sync_data(&mut self) -> std::io::Result<()> {
+            let mut state = self.state.lock().unwrap();
+            state.syncs += 1;
+            if !state.has_crashed() {
+                state.durable = state.current.clone();
+                state.unsynced.clear();
+            }
+            Ok(())
+        }
+    }
+
+    enum Operation {
+        Create(&'static str),
+        CreateDir(&'static str),
+        Delete(&'static str),
+        //path, offset to write at (end of the file if none) and what to write
+        Write(&'static str, Option<u64>, Vec<u8>),
+        Truncate(&'static str),
+        Defragment,
+        Compact,
+    }
+
+    impl Operation {
+        fn run(&self, vfs: &Vfs) {
+            match self {
+                Operation::Create(path) => drop(vfs.create(path).unwrap()),
+                Operation::CreateDir(path) => vfs.create_dir(path).unwrap(),
+                Operation::Delete(path) => vfs.delete(path).unwrap(),
+                Operation::Write(path, offset, bytes) => {
+                    let mut f = vfs.options().write(true).open(path).unwrap();
+                    match offset {
+                        Some(offset) => f.seek(SeekFrom::Start(*offset)).unwrap(),
+                        None => f.seek(SeekFrom::End(0)).unwrap(),
+                    };
+                    f.write_all(bytes).unwrap();
+                }
+                Operation::Truncate(path) => {
+                    drop(vfs.options().write(true).truncate(true).open(path).unwrap())
+                }
+                Operation::Defragment => vfs.defragment().unwrap(),
+                Operation::Compact => vfs.compact().unwrap(),
+            }
+        }
+    }
+
+    fn vfs_on_storage(storage: &FaultyStorage, durability: Durability) -> Vfs {
+        Vfs {
+            internal: Arc::new(VfsLock::new(
+                VfsInternal::open(Box::new(storage.clone()), durability).unwrap(),
+            )),
+        }
+    }
+
+    /// Every active entry by path, with the contents of files.
+    fn snapshot_tree(vfs: &Vfs) -> BTreeMap<String, Option<Vec<u8>>> {
+        let internal = vfs.internal.read();
+        let mut tree = BTreeMap::new();
+        let mut directories = vec![(String::new(), internal.get_file_struct_by_index(0).unwrap())];
+        while let Some((path, directory)) = directories.pop() {
+            let mut pointer = directory.contents;
+            while pointer != 0 {
+                let entity = internal.get_file_struct_by_index(pointer).unwrap();
+                let entity_path = format!("{}/{}", path, entity.name);
+                if entity.is_directory {
+                    tree.insert(entity_path.clone(), None);
+                    directories.push((entity_path, entity.clone()));
+                } else {
+                    let mut contents = vec![0u8; entity.size as usize];
+                    internal
+                        .read_file_data(&internal.get_extents(&entity).unwrap(), 0, &mut contents)
+                        .unwrap();
+                    tree.insert(entity_path, Some(contents));
+                }
+                pointer = entity.next;
+            }
+        }
+        tree
+    }
+
+    /// Checks that the allocation table marks exactly the pages something uses.
+    fn check_allocation(vfs: &Vfs) {
+        let internal = vfs.internal.read();
+        let mut used = vec![0, DEFAULT_SYSTEM_COMMIT_PAGE];
+        used.extend(
+            internal.allocation_table_page
+                ..(internal.allocation_table_page + internal.allocation_table_size),
+        );
+        used.extend(
+            internal.file_table_page..(internal.file_table_page + internal.file_table_size),
+        );
+
+        let mut directories = vec![internal.get_file_struct_by_index(0).unwrap()];
+        while let Some(directory) = directories.pop() {
+            let mut pointer = directory.contents;
+            while pointer != 0 {
+                let entity = internal.get_file_struct_by_index(pointer).unwrap();
+                if entity.is_directory {
+                    directories.push(entity.clone());
+                } else {
+                    for (page_number, extent_page) in internal.get_extent_map(&entity).unwrap() {
+                        used.push(page_number);
+                        used.extend(
+                            extent_page
+                                .extents
+                                .into_iter()
+                                .filter(|extent| !is_hole(extent))
+                                .flatten(),
+                        );
+                    }
+                }
+                pointer = entity.next;
+            }
+        }
+        used.sort();
+
+        let allocated: Vec<u64> = (0..internal.get_allocation_table_capacity())
+            .filter(|&page_number| internal.is_page_allocated(page_number).unwrap())
+            .collect();
+        assert_eq!(allocated, used);
+    }
+
+    /// Runs `operation` on the image left by `setup`, crashing after every possible write in turn,
+    /// and checks that reopening the image finds it as it was either before or after the operation,
+    /// as far as `durability` promises.
+    fn check_crash_consistency(setup: &[Operation], operation: &Operation, durability: Durability) {
+        let storage = FaultyStorage::default();
+        {
+            let vfs = Vfs {
+                internal: Arc::new(VfsLock::new(
+                    VfsInternal::create(Box::new(storage.clone()), &VfsOptions::default()).unwrap(),
+                )),
+            };
+            for setup_operation in setup {
+                setup_operation.run(&vfs);
+            }
+        }
+        let image = storage.state.lock().unwrap().current.clone();
+
+        //without full durability the contents of files may come back stale, only their sizes count
+        let compared =
+            |tree: BTreeMap<String, Option<Vec<u8>>>| -> BTreeMap<String, Option<Vec<u8>>> {
+                match durability {
+                    Durability::Full => tree,
+                    _ => tree
+                        .into_iter()
+                        .map(|(path, contents)| {
+                            (path, contents.map(|contents| vec![0; contents.len()]))
+                        })
+                        .collect(),
+                }
+            };
+
+        let storage = FaultyStorage::with_image(image.clone());
+        let vfs = vfs_on_storage(&storage, durability);
+        let before = compared(snapshot_tree(&vfs));
+        let writes_before = storage.state.lock().unwrap().writes;
+        operation.run(&vfs);
+        let after = compared(snapshot_tree(&vfs));
+        let number_of_writes = storage.state.lock().unwrap().writes - writes_before;
+        if durability > Durability::None {
+            assert!(storage.state.lock().unwrap().syncs > 0);
+        }
+        drop(vfs);
+
+        for crash_after in 0..=number_of_writes {
+            let storage = FaultyStorage::with_image(image.clone());
+            let vfs = vfs_on_storage(&storage, durability);
+            storage.crash_after(crash_after);
+            operation.run(&vfs);
+            drop(vfs);
+
+            //without syncs only a crash of the process itself leaves the image intact
+            for crash_image in storage.crash_images(durability > Durability::None) {
+                let vfs = Vfs::from_bytes(crash_image).unwrap();
+                let tree = compared(snapshot_tree(&vfs));
+                assert!(
+                    tree == before || tree == after,
+                    "crashing after write {} of {} with {:?} left {:?}",
+                    crash_after,
+                    number_of_writes,
+                    durability,
+                    tree.keys().collect::<Vec<_>>()
+                );
+                check_allocation(&vfs);
+            }
+        }
+    }
+
+    #[test]
+    fn test_crash_consistency() {
+        let mut script = vec![
+            Operation::CreateDir("dir"),
+            Operation::Create("dir/a"),
+            Operation::Write("dir/a", None, vec![b'a'; 5000]),
+            Operation::Write("dir/a", Some(100), vec![b'b'; 300]),
+            Operation::Write("dir/a", Some(4000), vec![b'c'; 9000]),
+            Operation::CreateDir("dir/sub"),
+            Operation::Create("dir/sub/b"),
+            Operation::Write("dir/sub/b", None, vec![b'd'; 100]),
+            Operation::Create("c"),
+            Operation::Write("c", Some(20000), vec![b'f'; 10]),
+            Operation::Write("c", Some(5000), vec![b'g'; 10]),
+            Operation::Truncate("dir/sub/b"),
+            Operation::Delete("dir/a"),
+            Operation::Compact,
+            Operation::Delete("dir"),
+        ];
+        //enough entries for the file table to run out of room
+        const NAMES: [&str; 26] = [
+            "f0", "f1", "f2", "f3", "f4", "f5", "f6", "f7", "f8", "f9", "f10", "f11", "f12", "f13",
+            "f14", "f15", "f16", "f17", "f18", "f19", "f20", "f21", "f22", "f23", "f24", "f25",
+        ];
+        script.extend(NAMES.iter().map(|&name| Operation::Create(name)));
+        script.push(Operation::Write("f25", None, vec![b'e'; 10]));
+        script.push(Operation::Delete("c"));
+        script.push(Operation::Defragment);
+
+        for durability in [Durability::None, Durability::MetadataOnly, Durability::Full] {
+            for i in 0..script.len() {
+                check_crash_consistency(&script[..i], &script[i], durability);
+            }
+        }
+    }
+
+    #[test]
+    fn test_set_durability() {
+        let storage = FaultyStorage::default();
+        let vfs = Vfs::with_storage(storage.clone()).unwrap();
+        assert_eq!(vfs.durability(), Durability::MetadataOnly);
+        let mut file = vfs.create("file").unwrap();
+
+        let syncs = storage.state.lock().unwrap().syncs;
+        file.write_all(b"synced").unwrap();
+        assert_eq!(storage.state.lock().unwrap().syncs, syncs + 2);
+
+        vfs.set_durability(Durability::None);
+        let syncs = storage.state.lock().unwrap().syncs;
+        file.write_all(b" or not").unwrap();
+        file.write_at(b"S", 0).unwrap();
+        assert_eq!(storage.state.lock().unwrap().syncs, syncs);
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        vfs.create_dir("dir").unwrap();
+        vfs.create("dir/file")
+            .unwrap()
+            .write_all(&[b'm'; 3 * DEFAULT_PAGE_SIZE as usize])
+            .unwrap();
+        vfs.delete("dir").unwrap();
+        assert_eq!(vfs.read_dir("dir").unwrap_err(), Error::DirectoryNotFound);
+
+        let mut storage = MemoryStorage::new();
+        assert_eq!(storage.is_empty().unwrap(), true);
+        storage.write_at(10, b"abc").unwrap();
+        assert_eq!(storage.len().unwrap(), 13);
+        let mut buf = [0u8; 4];
+        assert_eq!(
+            storage.read_at(10, &mut buf).unwrap_err().kind(),
+            std::io::ErrorKind::UnexpectedEof
+        );
+        storage.read_at(9, &mut buf).unwrap();
+        assert_eq!(&buf, b"\0abc");
+
+        //not empty, so it has to hold an image already
+        assert_eq!(Vfs::with_storage(storage).err(), Some(Error::NotAVfsImage));
+    }
+
+    #[test]
+    fn test_failed_storage_write() {
+        let storage = FaultyStorage::default();
+        let vfs = Vfs::with_storage(storage.clone()).unwrap();
+        let mut file = vfs.create("file").unwrap();
+        file.write_all(b"before").unwrap();
+
+        storage.state.lock().unwrap().fail_writes = true;
+        assert_eq!(
+            file.write_at(b"after", 0).unwrap_err().kind(),
+            std::io::ErrorKind::StorageFull
+        );
+        assert_eq!(
+            vfs.create("other").err(),
+            Some(Error::IO(std::io::ErrorKind::StorageFull))
+        );
+        storage.state.lock().unwrap().fail_writes = false;
+
+        //the errors came back instead of leaving the image locked up
+        let mut contents = String::new();
+        vfs.open_file("file")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "before");
+        vfs.create("other").unwrap();
+        check_allocation(&vfs);
+    }
+
+    #[test]
+    fn test_threads() {
+        fn is_send_sync<T: Send + Sync>() {}
+        is_send_sync::<Vfs>();
+        is_send_sync::<File>();
+        is_send_sync::<DirIterator>();
+
+        const THREADS: usize = 4;
+        const ROUNDS: usize = 25;
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        vfs.create_dir("pairs").unwrap();
+        std::thread::scope(|scope| {
+            for thread in 0..THREADS {
+                let vfs = &vfs;
+                scope.spawn(move || {
+                    let dir = format!("t{}", thread);
+                    vfs.create_dir(&dir).unwrap();
+                    for round in 0..ROUNDS {
+                        let path = format!("{}/f{}", dir, round);
+                        let contents = vec![(thread * ROUNDS + round) as u8; 1000 * (round + 1)];
+                        vfs.create(&path).unwrap().write_all(&contents).unwrap();
+
+                        let mut read_back = Vec::new();
+                        vfs.open_file(&path)
+                            .unwrap()
+                            .read_to_end(&mut read_back)
+                            .unwrap();
+                        assert_eq!(read_back, contents);
+
+                        if round % 2 == 0 {
+                            vfs.delete(&path).unwrap();
+                        }
+                    }
+                });
+            }
+
+            //files made by a transaction show up together or not at all
+            let vfs = &vfs;
+            scope.spawn(move || {
+                for round in 0..ROUNDS {
+                    vfs.transaction(|vfs| {
+                        vfs.create(&format!("pairs/a{}", round))?;
+                        vfs.create(&format!("pairs/b{}", round))?;
+                        Ok::<(), Error>(())
+                    })
+                    .unwrap();
+                }
+            });
+            scope.spawn(move || {
+                for _ in 0..ROUNDS * 4 {
+                    let names: Vec<String> = vfs
+                        .read_dir("pairs")
+                        .unwrap()
+                        .map(|entry| entry.unwrap().get_path())
+                        .collect();
+                    for name in &names {
+                        let other = name.replace("pairs/a", "pairs/b");
+                        assert!(names.contains(&other), "{} without {}", name, other);
+                    }
+                }
+            });
+        });
+
+        for thread in 0..THREADS {
+            let names: Vec<String> = vfs
+                .read_dir(&format!("t{}", thread))
+                .unwrap()
+                .map(|entry| entry.unwrap().get_path())
+                .collect();
+            assert_eq!(names.len(), ROUNDS / 2);
+            for round in (1..ROUNDS).step_by(2) {
+                let path = format!("t{}/f{}", thread, round);
+                let mut contents = Vec::new();
+                vfs.open_file(&path)
+                    .unwrap()
+                    .read_to_end(&mut contents)
+                    .unwrap();
+                assert_eq!(
+                    contents,
+                    vec![(thread * ROUNDS + round) as u8; 1000 * (round + 1)]
+                );
+            }
+        }
+        assert_eq!(vfs.read_dir("pairs").unwrap().count(), 2 * ROUNDS);
+        check_allocation(&vfs);
+    }
+
+    #[test]
+    fn test_seek_past_end() {
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        let mut file = vfs.create("file").unwrap();
+        assert_eq!(file.stream_position().unwrap(), 0);
+        assert_eq!(file.seek(SeekFrom::End(0)).unwrap(), 0);
+        assert_eq!(
+            file.seek(SeekFrom::Current(-1)).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            file.seek(SeekFrom::End(-1)).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+        assert_eq!(file.stream_position().unwrap(), 0);
+
+        file.write_all(&[b'a'; 5000]).unwrap();
+        assert_eq!(file.seek(SeekFrom::End(0)).unwrap(), 5000);
+        assert_eq!(file.seek(SeekFrom::End(-5001)).is_err(), true);
+        assert_eq!(file.seek(SeekFrom::Start(u64::MAX)).unwrap(), u64::MAX);
+        assert_eq!(file.seek(SeekFrom::Current(1)).is_err(), true);
+        assert_eq!(
+            file.write(b"overflow").unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+
+        //stale bytes left behind by a shrink must not come back
+        file.set_len(100).unwrap();
+        assert_eq!(file.seek(SeekFrom::End(3000)).unwrap(), 3100);
+        let mut buf = [0u8; 10];
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+        assert_eq!(file.write(&[]).unwrap(), 0);
+        assert_eq!(file.metadata().unwrap().size, 100);
+        file.write_all(b"after the gap").unwrap();
+        file.write_at(b"x", 10000).unwrap();
+        assert_eq!(file.metadata().unwrap().size, 10001);
+
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        let mut expected = vec![b'a'; 100];
+        expected.resize(3100, 0);
+        expected.extend_from_slice(b"after the gap");
+        expected.resize(10000, 0);
+        expected.push(b'x');
+        assert_eq!(contents, expected);
+    }
+
+    #[test]
+    fn test_sparse_files() {
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        let page_size = DEFAULT_PAGE_SIZE;
+        let empty_pages = vfs.internal.read().get_number_of_pages().unwrap();
+
+        //pages freed by another file still hold its bytes
+        let mut file = vfs.create("old").unwrap();
+        file.write_all(&[b'a'; 3 * DEFAULT_PAGE_SIZE as usize])
+            .unwrap();
+        vfs.delete("old").unwrap();
+
+        let mut file = vfs.create("sparse").unwrap();
+        file.seek(SeekFrom::Start(page_size + 10)).unwrap();
+        file.write_all(b"first").unwrap();
+        file.seek(SeekFrom::Start(100 * 1024 * 1024)).unwrap();
+        file.write_all(b"last").unwrap();
+        assert_eq!(file.metadata().unwrap().size, 100 * 1024 * 1024 + 4);
+        {
+            let internal = vfs.internal.read();
+            let metadata = file.get_metadata();
+            let extents = internal.get_extents(&metadata).unwrap();
+            assert_eq!(extents.iter().filter(|extent| is_hole(extent)).count(), 2);
+            //two pages of data and one for the extent map
+            assert_eq!(internal.get_number_of_pages().unwrap(), empty_pages + 3);
+        }
+
+        let mut buf = vec![0xFFu8; 2 * page_size as usize];
+        assert_eq!(file.read_at(&mut buf, 0).unwrap(), buf.len());
+        let mut expected = vec![0u8; 2 * page_size as usize];
+        expected[(page_size as usize + 10)..(page_size as usize + 15)].copy_from_slice(b"first");
+        assert_eq!(buf, expected);
+        file.read_exact_at(&mut buf, 50 * 1024 * 1024).unwrap();
+        assert_eq!(buf.iter().all(|&byte| byte == 0), true);
+
+        //filling a hole in the middle
+        file.write_at(b"middle", 4 * page_size - 3).unwrap();
+        let mut buf = vec![0xFFu8; 3 * page_size as usize];
+        file.read_exact_at(&mut buf, 2 * page_size).unwrap();
+        let mut expected = vec![0u8; 3 * page_size as usize];
+        expected[(2 * page_size as usize - 3)..(2 * page_size as usize + 3)]
+            .copy_from_slice(b"middle");
+        assert_eq!(buf, expected);
+        check_allocation(&vfs);
+
+        //growing only moves the size, shrinking into a hole keeps what comes before it
+        let pages_before = vfs.internal.read().get_number_of_pages().unwrap();
+        file.set_len(1024 * 1024 * 1024).unwrap();
+        assert_eq!(
+            vfs.internal.read().get_number_of_pages().unwrap(),
+            pages_before
+        );
+        file.set_len(10 * page_size).unwrap();
+        check_allocation(&vfs);
+        let mut buf = [0u8; 5];
+        file.read_exact_at(&mut buf, page_size + 10).unwrap();
+        assert_eq!(&buf, b"first");
+        let mut buf = vec![0xFFu8; page_size as usize];
+        file.read_exact_at(&mut buf, 9 * page_size).unwrap();
+        assert_eq!(buf.iter().all(|&byte| byte == 0), true);
+
+        vfs.delete("sparse").unwrap();
+        check_allocation(&vfs);
+        assert_eq!(
+            vfs.internal.read().get_number_of_pages().unwrap(),
+            empty_pages
+        );
+    }
+
+    #[test]
+    fn test_allocate() {
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        let page_size = DEFAULT_PAGE_SIZE as usize;
+        let mut file = vfs.create("old").unwrap();
+        file.write_all(&vec![b'a'; 8 * page_size]).unwrap();
+        vfs.delete("old").unwrap();
+        let allocated_pages = || {
+            let internal = vfs.internal.read();
+            (0..internal.get_allocation_table_capacity())
+                .filter(|&page_number| internal.is_page_allocated(page_number).unwrap())
+                .count()
+        };
+        let pages_before = allocated_pages();
+
+        let mut file = vfs.create("log").unwrap();
+        file.allocate(50 * page_size as u64).unwrap();
+        assert_eq!(file.metadata().unwrap().size, 0);
+        //50 pages of data and one for the extent map
+        assert_eq!(allocated_pages(), pages_before + 51);
+        let extents = vfs
+            .internal
+            .read()
+            .get_extents(&file.get_metadata())
+            .unwrap();
+        assert_eq!(
+            extents
+                .iter()
+                .map(|extent| extent.end - extent.start)
+                .sum::<u64>(),
+            50
+        );
+        check_allocation(&vfs);
+
+        //appends stay inside the reserved pages, bytes the file never had read as zeros
+        for _ in 0..10 {
+            file.write_all(&vec![b'b'; 3 * page_size]).unwrap();
+        }
+        file.write_at(b"c", 45 * page_size as u64).unwrap();
+        assert_eq!(
+            vfs.internal
+                .read()
+                .get_extents(&file.get_metadata())
+                .unwrap(),
+            extents
+        );
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        let mut expected = vec![b'b'; 30 * page_size];
+        expected.resize(45 * page_size, 0);
+        expected.push(b'c');
+        assert_eq!(contents, expected);
+
+        //reserving less than is there already does nothing
+        file.allocate(10).unwrap();
+        assert_eq!(
+            vfs.internal
+                .read()
+                .get_extents(&file.get_metadata())
+                .unwrap(),
+            extents
+        );
+
+        file.set_len(page_size as u64).unwrap();
+        check_allocation(&vfs);
+        assert_eq!(
+            vfs.internal
+                .read()
+                .get_extents(&file.get_metadata())
+                .unwrap()
+                .len(),
+            1
+        );
+
+        //holes below the size read as zeros once they get pages
+        vfs.create("keep").unwrap();
+        let file = vfs.create("f").unwrap();
+        file.set_len(4 * page_size as u64).unwrap();
+        file.allocate(4 * page_size as u64).unwrap();
+        let mut contents = vec![b'x'; 4 * page_size];
+        file.read_exact_at(&mut contents, 0).unwrap();
+        assert_eq!(contents, vec![0; 4 * page_size]);
+        check_allocation(&vfs);
+
+        let read_only = vfs.options().read(true).open("log").unwrap();
+        assert_eq!(
+            read_only.allocate(100),
+            Err(Error::IO(std::io::ErrorKind::PermissionDenied))
+        );
+    }
+
+    #[test]
+    fn test_allocation_policy() {
+        let page_size = DEFAULT_PAGE_SIZE as usize;
+        let pages_of = |vfs: &Vfs, path: &str| {
+            let internal = vfs.internal.read();
+            let (_, file) = internal.get_file_struct_by_path(path).unwrap();
+            let mut pages: Vec<u64> = internal
+                .get_extent_map(&file)
+                .unwrap()
+                .iter()
+                .map(|(page, _)| *page)
+                .collect();
+            pages.extend(internal.get_extents(&file).unwrap().into_iter().flatten());
+            pages
+        };
+
+        //leaves a free run of 4 pages and after it one of 2
+        let fragmented = |placement: Placement| {
+            let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+            vfs.set_allocation_policy(AllocationPolicy {
+                placement,
+                ..AllocationPolicy::default()
+            });
+            for (path, pages) in [("big", 3), ("a", 1), ("small", 1), ("b", 1)] {
+                vfs.create(path)
+                    .unwrap()
+                    .write_all(&vec![b'x'; pages * page_size])
+                    .unwrap();
+            }
+            let big = pages_of(&vfs, "big");
+            let small = pages_of(&vfs, "small");
+            vfs.delete("big").unwrap();
+            vfs.delete("small").unwrap();
+            (vfs, big, small)
+        };
+        let is_inside = |pages: &[u64], run: &[u64]| pages.iter().all(|page| run.contains(page));
+
+        let (vfs, big, _) = fragmented(Placement::FirstFit);
+        vfs.create("new").unwrap().write_all(b"x").unwrap();
+        assert!(is_inside(&pages_of(&vfs, "new"), &big));
+        check_allocation(&vfs);
+
+        let (vfs, _, small) = fragmented(Placement::BestFit);
+        vfs.create("new").unwrap().write_all(b"x").unwrap();
+        assert!(is_inside(&pages_of(&vfs, "new"), &small));
+        check_allocation(&vfs);
+
+        //the free runs are too small for the large file, the next one carries on after it
+        let (vfs, big, small) = fragmented(Placement::NextFit);
+        vfs.create("large")
+            .unwrap()
+            .write_all(&vec![b'x'; 8 * page_size])
+            .unwrap();
+        vfs.create("new").unwrap().write_all(b"x").unwrap();
+        let end_of_large = pages_of(&vfs, "large").into_iter().max().unwrap();
+        assert!(pages_of(&vfs, "new")
+            .iter()
+            .all(|page| *page > end_of_large && !big.contains(page) && !small.contains(page)));
+        check_allocation(&vfs);
+
+        //two files appended to in turns end up in far fewer pieces when they reserve pages ahead
+        let appended_extents = |growth: Growth| {
+            let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+            vfs.set_allocation_policy(AllocationPolicy {
+                growth,
+                ..AllocationPolicy::default()
+            });
+            let mut first = vfs.create("first").unwrap();
+            let mut second = vfs.create("second").unwrap();
+            for _ in 0..16 {
+                first.write_all(&vec![b'1'; page_size]).unwrap();
+                second.write_all(&vec![b'2'; page_size]).unwrap();
+            }
+            check_allocation(&vfs);
+
+            let mut contents = Vec::new();
+            vfs.open_file("first")
+                .unwrap()
+                .read_to_end(&mut contents)
+                .unwrap();
+            assert_eq!(contents, vec![b'1'; 16 * page_size]);
+            let metadata = first.metadata().unwrap();
+            assert_eq!(metadata.size, 16 * page_size as u64);
+            assert!(metadata.capacity >= metadata.size);
+            let extents = vfs
+                .internal
+                .read()
+                .get_extents(&first.get_metadata())
+                .unwrap();
+            extents.len()
+        };
+        assert_eq!(appended_extents(Growth::Exact), 16);
+        assert!(appended_extents(Growth::OneAndAHalf) <= 8);
+        assert!(appended_extents(Growth::Double) <= 5);
+
+        //the capacity doubles with every page boundary crossed and is given back on truncation
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        vfs.set_allocation_policy(AllocationPolicy {
+            growth: Growth::Double,
+            ..AllocationPolicy::default()
+        });
+        let mut file = vfs.create("log").unwrap();
+        let mut capacities = Vec::new();
+        for _ in 0..5 {
+            file.write_all(&vec![b'a'; page_size]).unwrap();
+            capacities.push(file.metadata().unwrap().capacity / page_size as u64);
+        }
+        assert_eq!(capacities, vec![1, 2, 4, 4, 8]);
+
+        //bytes past the size in spare pages still read back as zeros once the file grows over them
+        file.set_len(5 * page_size as u64 + 10).unwrap();
+        let mut tail = vec![1u8; 10];
+        file.read_exact_at(&mut tail, 5 * page_size as u64).unwrap();
+        assert_eq!(tail, vec![0u8; 10]);
+
+        file.set_len(page_size as u64).unwrap();
+        assert_eq!(file.metadata().unwrap().capacity, page_size as u64);
+        check_allocation(&vfs);
+    }
+
+    #[test]
+    fn test_defragment_and_compact() {
+        let page_size = DEFAULT_PAGE_SIZE as usize;
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        let file_pages = |vfs: &Vfs, path: &str| {
+            let internal = vfs.internal.read();
+            let (_, file) = internal.get_file_struct_by_path(path).unwrap();
+            internal.get_file_pages(&file).unwrap()
+        };
+        let is_contiguous = |pages: &[u64]| pages.windows(2).all(|pair| pair[1] == pair[0] + 1);
+        let read_all = |vfs: &Vfs, path: &str| {
+            let mut contents = Vec::new();
+            vfs.open_file(path)
+                .unwrap()
+                .read_to_end(&mut contents)
+                .unwrap();
+            contents
+        };
+        let image_len = |vfs: &Vfs| vfs.internal.read().get_physical_len().unwrap();
+
+        vfs.create("filler")
+            .unwrap()
+            .write_all(&vec![b'f'; 20 * page_size])
+            .unwrap();
+        //enough entries to move the file table behind the filler
+        vfs.create_dir("dir").unwrap();
+        for i in 0..30 {
+            vfs.create(&format!("dir/{i}")).unwrap();
+        }
+        let mut a = vfs.create("a").unwrap();
+        let mut b = vfs.create("dir/b").unwrap();
+        for i in 0..6 {
+            a.write_all(&vec![i; page_size]).unwrap();
+            b.write_all(&vec![100 + i; page_size]).unwrap();
+        }
+        //a hole in the middle, after 3 pages of data
+        b.write_at(b"end", 20 * page_size as u64).unwrap();
+        let mut last = vfs.create("last").unwrap();
+        last.write_all(&vec![b'l'; 3 * page_size]).unwrap();
+        vfs.delete("filler").unwrap();
+
+        let expected_a = read_all(&vfs, "a");
+        let expected_b = read_all(&vfs, "dir/b");
+        assert!(!is_contiguous(&file_pages(&vfs, "a")));
+        let file_table_page = vfs.internal.read().file_table_page;
+        let length_before = image_len(&vfs);
+
+        let mut reports = Vec::new();
+        vfs.defragment_with_progress(|progress| reports.push(progress))
+            .unwrap();
+        check_allocation(&vfs);
+        let report = *reports.last().unwrap();
+        assert_eq!(report.done, report.total);
+        assert_eq!(reports.len() as u64, report.total);
+        assert!(report.pages_moved > 0);
+
+        for path in ["a", "dir/b", "last"] {
+            assert!(is_contiguous(&file_pages(&vfs, path)), "{path}");
+        }
+        assert!(vfs.internal.read().file_table_page < file_table_page);
+        assert!(image_len(&vfs) < length_before);
+        assert_eq!(read_all(&vfs, "a"), expected_a);
+        assert_eq!(read_all(&vfs, "dir/b"), expected_b);
+        assert_eq!(
+            vfs.internal
+                .read()
+                .get_extents(&b.get_metadata())
+                .unwrap()
+                .iter()
+                .filter(|extent| is_hole(extent))
+                .count(),
+            1
+        );
+
+        //handles that were open keep working on the moved pages
+        let mut contents = Vec::new();
+        last.seek(SeekFrom::Start(0)).unwrap();
+        last.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, vec![b'l'; 3 * page_size]);
+        last.write_all(b"more").unwrap();
+
+        //compacting fills every free page before the end of the image
+        vfs.delete("a").unwrap();
+        let expected_last = read_all(&vfs, "last");
+        let length_before = image_len(&vfs);
+        vfs.compact().unwrap();
+        check_allocation(&vfs);
+        {
+            let internal = vfs.internal.read();
+            assert_eq!(
+                internal
+                    .get_free_page_runs(internal.get_number_of_pages().unwrap())
+                    .unwrap(),
+                Vec::new()
+            );
+        }
+        assert!(image_len(&vfs) < length_before);
+        assert_eq!(read_all(&vfs, "last"), expected_last);
+        assert_eq!(read_all(&vfs, "dir/b"), expected_b);
+
+        //nothing left to do
+        let mut reports = Vec::new();
+        vfs.compact_with_progress(|progress| reports.push(progress))
+            .unwrap();
+        assert_eq!(reports.last().unwrap().pages_moved, 0);
+
+        let reopened = Vfs::from_bytes(vfs.to_bytes().unwrap()).unwrap();
+        assert_eq!(reopened.journal_recovery(), JournalRecovery::Clean);
+        assert_eq!(read_all(&reopened, "last"), expected_last);
+        assert_eq!(read_all(&reopened, "dir/b"), expected_b);
+
+        let bytes: &'static [u8] = Box::leak(vfs.to_bytes().unwrap().into_boxed_slice());
+        assert_eq!(
+            Vfs::from_static(bytes).unwrap().defragment(),
+            Err(Error::ReadOnly)
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let _image = TestImage::new("stats.vfs");
+        let page_size = DEFAULT_PAGE_SIZE as usize;
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        let slots = (DEFAULT_PAGE_SIZE - 1) / FILE_STRUCT_SIZE as u64;
+        //header, allocation table, journal and file table
+        assert_eq!(
+            vfs.stats().unwrap(),
+            VfsStats {
+                page_size: DEFAULT_PAGE_SIZE,
+                total_pages: 4,
+                used_pages: 4,
+                free_pages: 0,
+                largest_free_run: 0,
+                file_slots_used: 1,
+                file_slots_free: slots - 1,
+            }
+        );
+
+        vfs.create_dir("dir").unwrap();
+        for path in ["dir/a", "b", "c"] {
+            vfs.create(path)
+                .unwrap()
+                .write_all(&vec![b'x'; 3 * page_size])
+                .unwrap();
+        }
+        vfs.delete("dir").unwrap();
+        vfs.delete("c").unwrap();
+        //the image ends with the last page that is used
+        let stats = vfs.stats().unwrap();
+        assert_eq!(stats.total_pages, 12);
+        assert_eq!(stats.used_pages, 4 + 4);
+        assert_eq!(stats.free_pages, 4);
+        assert_eq!(stats.largest_free_run, 4);
+        assert_eq!(stats.file_slots_used, 2);
+        assert_eq!(stats.file_slots_used + stats.file_slots_free, slots);
+
+        //with a maximum size the pages the image may still grow into count as free
+        let vfs = Vfs::create_with(
+            "stats.vfs",
+            VfsOptions::new().max_size(32 * DEFAULT_PAGE_SIZE),
+        )
+        .unwrap();
+        vfs.create("a")
+            .unwrap()
+            .write_all(&vec![b'x'; page_size])
+            .unwrap();
+        vfs.create("b")
+            .unwrap()
+            .write_all(&vec![b'x'; page_size])
+            .unwrap();
+        vfs.delete("a").unwrap();
+        let stats = vfs.stats().unwrap();
+        assert_eq!(stats.total_pages, 32);
+        assert_eq!(stats.used_pages, 4 + 2);
+        assert_eq!(stats.free_pages, 26);
+        assert_eq!(stats.largest_free_run, 24);
+
+        //a file that needs more than the largest run gets split, one that needs more than is free fails
+        let mut file = vfs.create("c").unwrap();
+        assert!(file.write_all(&vec![b'x'; 26 * page_size]).is_err());
+        file.write_all(&vec![b'x'; 25 * page_size]).unwrap();
+        assert_eq!(vfs.stats().unwrap().free_pages, 0);
+    }
+
+    #[test]
+    fn test_layout_report() {
+        let page_size = DEFAULT_PAGE_SIZE as usize;
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        vfs.create_dir("dir").unwrap();
+        for (path, pages) in [("dir/a", 2), ("b", 1), ("c", 1), ("d", 1), ("e", 1)] {
+            vfs.create(path)
+                .unwrap()
+                .write_all(&vec![b'x'; pages * page_size])
+                .unwrap();
+        }
+        vfs.delete("b").unwrap();
+        vfs.delete("d").unwrap();
+
+        let region = |pages: std::ops::Range<u64>, owner: PageOwner| PageRegion { pages, owner };
+        let mut regions = vec![
+            region(0..1, PageOwner::SystemHeader),
+            region(1..2, PageOwner::AllocationTable),
+            region(2..3, PageOwner::Journal),
+            region(3..4, PageOwner::FileTable),
+            region(4..6, PageOwner::File("dir/a".to_owned())),
+            region(6..7, PageOwner::ExtentMap("dir/a".to_owned())),
+            region(9..10, PageOwner::File("c".to_owned())),
+            region(10..11, PageOwner::ExtentMap("c".to_owned())),
+            region(13..14, PageOwner::File("e".to_owned())),
+            region(14..15, PageOwner::ExtentMap("e".to_owned())),
+        ];
+        assert_eq!(
+            vfs.layout_report().unwrap(),
+            LayoutReport {
+                page_size: DEFAULT_PAGE_SIZE,
+                regions: regions.clone(),
+                free_runs: vec![7..9, 11..13],
+                fragmentation: 0.5,
+            }
+        );
+
+        //a page nothing uses shows up as well
+        vfs.internal
+            .write()
+            .allocate_page_range(7..8, true)
+            .unwrap();
+        regions.insert(6, region(7..8, PageOwner::Unknown));
+        let report = vfs.layout_report().unwrap();
+        assert_eq!(report.regions, regions);
+        assert_eq!(report.free_runs, vec![8..9, 11..13]);
+        vfs.internal
+            .write()
+            .allocate_page_range(7..8, false)
+            .unwrap();
+
+        vfs.defragment().unwrap();
+        let report = vfs.layout_report().unwrap();
+        assert_eq!(report.free_runs, Vec::new());
+        assert_eq!(report.fragmentation, 0.0);
+    }
+
+    #[test]
+    fn test_empty_write_past_end() {
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        let mut file = vfs.create("file").unwrap();
+        file.seek(SeekFrom::Start(1000)).unwrap();
+        assert_eq!(file.write(&[]).unwrap(), 0);
+        assert_eq!(file.write_at(&[], 1000).unwrap(), 0);
+        assert_eq!(file.stream_position().unwrap(), 1000);
+
+        //the vfs is still usable afterwards
+        vfs.create("g").unwrap();
+        file.write_all(b"x").unwrap();
+        assert_eq!(file.metadata().unwrap().size, 1001);
+        check_allocation(&vfs);
+    }
+
+    #[test]
+    fn test_positional_io() {
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        let contents: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+        vfs.create("index").unwrap().write_all(&contents).unwrap();
+
+        let mut file = vfs.open_file("index").unwrap();
+        file.seek(SeekFrom::Start(7)).unwrap();
+        let mut buf = [0u8; 100];
+        assert_eq!(file.read_at(&mut buf, 4090).unwrap(), 100);
+        assert_eq!(buf[..], contents[4090..4190]);
+        assert_eq!(file.read_at(&mut buf, 9950).unwrap(), 50);
+        assert_eq!(buf[..50], contents[9950..]);
+        assert_eq!(file.read_at(&mut buf, 10000).unwrap(), 0);
+        assert_eq!(file.read_at(&mut buf, 20000).unwrap(), 0);
+        assert_eq!(
+            file.read_exact_at(&mut buf, 9950).unwrap_err().kind(),
+            std::io::ErrorKind::UnexpectedEof
+        );
+        file.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf[..], contents[..100]);
+
+        assert_eq!(file.write_at(b"middle", 5000).unwrap(), 6);
+        assert_eq!(file.write_at(b"end", 10000).unwrap(), 3);
+        assert_eq!(file.stream_position().unwrap(), 7);
+        assert_eq!(file.metadata().unwrap().size, 10003);
+        let mut buf = [0u8; 6];
+        file.read_exact_at(&mut buf, 5000).unwrap();
+        assert_eq!(&buf, b"middle");
+
+        //one handle shared by several readers
+        std::thread::scope(|scope| {
+            for thread in 0..4u64 {
+                let file = &file;
+                let contents = &contents;
+                scope.spawn(move || {
+                    let mut buf = [0u8; 1000];
+                    let offset = thread * 1000;
+                    file.read_exact_at(&mut buf, offset).unwrap();
+                    assert_eq!(
+                        buf[..],
+                        contents[(offset as usize)..(offset as usize + 1000)]
+                    );
+                });
+            }
+        });
+
+        let appending = vfs.options().append(true).open("index").unwrap();
+        appending.write_at(b"!", 0).unwrap();
+        let mut buf = [0u8; 4];
+        file.read_exact_at(&mut buf, 10000).unwrap();
+        assert_eq!(&buf, b"end!");
+
+        let read_only = vfs.options().read(true).open("index").unwrap();
+        assert_eq!(
+            read_only.write_at(b"x", 0).unwrap_err().kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        vfs.create_dir("assets").unwrap();
+        vfs.create("assets/a.txt")
+            .unwrap()
+            .write_all(b"embedded")
+            .unwrap();
+        let bytes = vfs.to_bytes().unwrap();
+
+        let copy = Vfs::from_bytes(bytes.clone()).unwrap();
+        let mut contents = String::new();
+        copy.open_file("assets/a.txt")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "embedded");
+        copy.create("assets/b.txt").unwrap();
+        assert_eq!(vfs.to_bytes().unwrap(), bytes);
+        assert_ne!(copy.to_bytes().unwrap(), bytes);
+
+        assert_eq!(
+            Vfs::from_bytes(b"\xFF\xD8\xFF\xE0 not an image".to_vec()).err(),
+            Some(Error::NotAVfsImage)
+        );
+        assert_eq!(Vfs::from_bytes(Vec::new()).err(), Some(Error::NotAVfsImage));
+    }
+
+    #[test]
+    fn test_from_static() {
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        vfs.create("file").unwrap().write_all(b"static").unwrap();
+        let bytes: &'static [u8] = Vec::leak(vfs.to_bytes().unwrap());
+
+        let vfs = Vfs::from_static(bytes).unwrap();
+        assert_eq!(vfs.journal_recovery(), JournalRecovery::Clean);
+        let mut file = vfs.open_file("file").unwrap();
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "static");
+
+        assert_eq!(
+            file.write(b"x").unwrap_err().kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(vfs.create("other").err(), Some(Error::ReadOnly));
+        assert_eq!(vfs.create_dir("dir").err(), Some(Error::ReadOnly));
+        assert_eq!(vfs.delete("file"), Err(Error::ReadOnly));
+        assert_eq!(vfs.rename("file", "moved"), Err(Error::ReadOnly));
+        assert_eq!(
+            vfs.options().write(true).open("file").err(),
+            Some(Error::ReadOnly)
+        );
+        assert_eq!(vfs.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_to_bytes_matches_file() {
+        let _image = TestImage::new("to_bytes.vfs");
+        let vfs = Vfs::create_new("to_bytes.vfs").unwrap();
+        vfs.create("file").unwrap().write_all(&[7; 5000]).unwrap();
+        assert_eq!(vfs.to_bytes().unwrap(), fs::read("to_bytes.vfs").unwrap());
+    }
+
+    #[test]
+    fn test_file_storage() {
+        let _image = TestImage::new("file_storage.vfs");
+        {
+            let storage = FileStorage::create_new("file_storage.vfs").unwrap();
+            let vfs = Vfs::with_storage(storage).unwrap();
+            vfs.create("file").unwrap().write_all(b"on disk").unwrap();
+        }
+
+        let vfs = Vfs::with_storage(FileStorage::open("file_storage.vfs").unwrap()).unwrap();
+        let mut contents = String::new();
+        vfs.open_file("file")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "on disk");
+    }
+
+    #[test]
+    fn test_read_dir_deleted_files() {
+        let vfs = Vfs::with_storage(MemoryStorage::new()).unwrap();
+        vfs.create_dir("dir").unwrap();
+        vfs.create("dir/file1").unwrap();
+        vfs.create("dir/file2").unwrap();
+        vfs.create("dir/file3").unwrap();
+
+        let dir = vfs.read_dir("dir").unwrap();
+
+        vfs.delete("dir/file2").unwrap();
+
+        let mut found1 = false;
+        let mut found2 = false;
+        let mut found3 = false;
+        for entry in dir {
+            if entry.is_ok() {
+                let entry = entry.unwrap();
+                if entry.get_path() == "dir/file1" {
+                    found1 = true;
+                }
+
+                if entry.get_path() == "dir/file2" {
+                    found2 = true;
+                }
+
+                if entry.get_path() == "dir/file3" {
+                    found3 = true;
+                }
+            } else {
+                assert_eq!(entry.unwrap_err(), Error::FileNotFound);
+            }
+        }
+
+        assert_eq!(found1, true);
+        assert_eq!(found2, false);
+        assert_eq!(found3, true);
+    }
+
+    #[test]
+    fn example() -> Result<(), Error> {
+        let vfs = Vfs::with_storage(MemoryStorage::new())?;
+
+        vfs.create_dir("rs")?;
+        {
+            let mut f1 = vfs.create("rs/abc.txt")?;
+            let mut f2 = vfs.create("rs/def.txt")?;
+
+            f1.write_all(b"hello")?;
+            f2.write_all(b"world")?;
+        }
+
+        let mut data = String::new();
+        for entry in vfs.read_dir("rs")? {
+            let entry = entry?;
+            data.clear();
+
+            let mut file = vfs.open_entry(entry)?;
+            file.read_to_string(&mut data)?;
+
+            print!("{}", data);
+        }
+        println!();
+        Ok(())
+    }
+}
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
-use std::path::Path;
-use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{
+    Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak,
+};
+use std::thread::{self, ThreadId};
 use std::time::SystemTime;
 
-const DEFAULT_PAGE_SIZE: u64 = 4096;
-const DEFAULT_ALLOCATION_TABLE_PAGE: u64 = 1;
-const DEFAULT_SYSTEM_COMMIT_PAGE: u64 = 2;
-const DEFAULT_FILE_TABLE_PAGE: u64 = 3;
+#[cfg(feature = "async")]
+mod async_vfs;
+#[cfg(feature = "async")]
+pub use async_vfs::{AsyncFile, AsyncVfs};
+
+const MAGIC: [u8; 4] = *b"VFSL";
+const FORMAT_VERSION: u32 = 3;
+const HEADER_SIZE: usize = 56;
+
+const DEFAULT_PAGE_SIZE: u64 = 4096;
+const MIN_PAGE_SIZE: u64 = 512;
+const MAX_PAGE_SIZE: u64 = 1 << 20;
+const DEFAULT_ALLOCATION_TABLE_PAGE: u64 = 1;
+const DEFAULT_SYSTEM_COMMIT_PAGE: u64 = 2;
+const DEFAULT_FILE_TABLE_PAGE: u64 = 3;
+//record count, length of the records, the page they overflow to (0 when they fit in the commit page),
+//sequence number and checksum of the header
+const JOURNAL_HEADER_SIZE: u64 = 5 * size_of::<u64>() as u64;
+
+pub struct Vfs {
+    internal: Arc<VfsLock>,
+}
+
+impl Vfs {
+    /// Opens an existing image, same as [`Vfs::open_existing`].
+    pub fn open(filename: &str) -> Result<Vfs, Error> {
+        Vfs::open_existing(filename)
+    }
+
+    /// Opens an existing image, failing if there is nothing at `filename`.
+    pub fn open_existing(filename: &str) -> Result<Vfs, Error> {
+        Vfs::open_with(filename, Durability::default())
+    }
+
+    /// Opens an existing image, syncing commits to disk as `durability` asks for.
+    pub fn open_with(filename: &str, durability: Durability) -> Result<Vfs, Error> {
+        let storage = FileStorage::open(filename)?;
+        Ok(Vfs {
+            internal: Arc::new(VfsLock::new(VfsInternal::open(
+                Box::new(storage),
+                durability,
+            )?)),
+        })
+    }
+
+    /// Whether opening the image had to replay or throw away an interrupted commit.
+    pub fn journal_recovery(&self) -> JournalRecovery {
+        self.internal.read().recovery
+    }
+
+    /// Changes where file pages go and how many are reserved ahead from now on, see [`AllocationPolicy`].
+    pub fn set_allocation_policy(&self, policy: AllocationPolicy) {
+        self.internal.write().allocation_policy = policy;
+    }
+
+    pub fn allocation_policy(&self) -> AllocationPolicy {
+        self.internal.read().allocation_policy
+    }
+
+    /// Changes when commits call `sync_data` from now on, see [`Durability`].
+    pub fn set_durability(&self, durability: Durability) {
+        self.internal.write().durability = durability;
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.internal.read().durability
+    }
+
+    /// Creates a new image, failing if something already exists at `filename`.
+    pub fn create_new(filename: &str) -> Result<Vfs, Error> {
+        Vfs::create_with(filename, &VfsOptions::default())
+    }
+
+    /// Creates a new image with the given geometry, failing if something already exists at `filename`.
+    pub fn create_with(filename: &str, options: &VfsOptions) -> Result<Vfs, Error> {
+        options.validate()?;
+        let storage = FileStorage::create_new(filename)?;
+        Ok(Vfs {
+            internal: Arc::new(VfsLock::new(VfsInternal::create(
+                Box::new(storage),
+                options,
+            )?)),
+        })
+    }
+
+    /// Opens the image at `filename`, creating an empty one if there is nothing there yet.
+    pub fn open_or_create(filename: &str) -> Result<Vfs, Error> {
+        match Vfs::create_new(filename) {
+            Err(Error::IO(std::io::ErrorKind::AlreadyExists)) => Vfs::open_existing(filename),
+            result => result,
+        }
+    }
+
+    /// Opens the image kept in `storage`, creating an empty one with the default geometry if the
+    /// storage is empty.
+    pub fn with_storage<S: Storage + 'static>(storage: S) -> Result<Vfs, Error> {
+        let internal = if storage.len()? == 0 && !storage.is_read_only() {
+            VfsInternal::create(Box::new(storage), &VfsOptions::default())?
+        } else {
+            VfsInternal::open(Box::new(storage), Durability::default())?
+        };
+        Ok(Vfs {
+            internal: Arc::new(VfsLock::new(internal)),
+        })
+    }
+
+    /// Opens an image held in memory, changes stay in memory until [`Vfs::to_bytes`] hands them back.
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Vfs, Error> {
+        Ok(Vfs {
+            internal: Arc::new(VfsLock::new(VfsInternal::open(
+                Box::new(MemoryStorage::from(bytes)),
+                Durability::default(),
+            )?)),
+        })
+    }
+
+    /// Opens an image embedded in the binary without copying it. The image can only be read,
+    /// anything that would change it fails with [`Error::ReadOnly`].
+    pub fn from_static(bytes: &'static [u8]) -> Result<Vfs, Error> {
+        Ok(Vfs {
+            internal: Arc::new(VfsLock::new(VfsInternal::open(
+                Box::new(StaticStorage::from(bytes)),
+                Durability::None,
+            )?)),
+        })
+    }
+
+    /// Copies out the whole image, byte for byte what it would be on disk.
+    /// Changes of a transaction that is still running are not part of it.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let internal = self.internal.read();
+        let storage = internal.storage.read().unwrap();
+        let mut bytes = vec![0u8; storage.len()? as usize];
+        storage.read_at(0, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn create_dir(&self, path: &str) -> Result<(), Error> {
+        self.create_file_entity(path, true)?;
+        Ok(())
+    }
+
+    pub fn create(&self, path: &str) -> Result<File, Error> {
+        self.create_file_entity(path, false)
+    }
+
+    fn create_file_entity(&self, path: &str, is_directory: bool) -> Result<File, Error> {
+        let new_index = self.internal.write().create_entity(path, is_directory)?;
+
+        Ok(File {
+            internal: Arc::downgrade(&self.internal),
+            file_index: new_index,
+            cursor: 0,
+            path: path.to_owned(),
+            readable: true,
+            writable: true,
+            append: false,
+        })
+    }
+
+    pub fn print_tree(&self) -> Result<(), Error> {
+        let internal = self.internal.read();
+        let root_contents = internal.get_file_struct_by_index(0)?.contents;
+        internal.print_tree_recursive(root_contents, 0)
+    }
+
+    pub fn read_dir(&self, path: &str) -> Result<DirIterator, Error> {
+        let internal = self.internal.read();
+        let temp = internal.get_file_struct_by_path(path);
+
+        if temp.is_err() {
+            return Err(Error::DirectoryNotFound);
+        }
+
+        let dir = temp.unwrap().1;
+
+        let mut entry_names = Vec::new();
+
+        let mut pointer = dir.contents;
+        while pointer != 0 {
+            let entry = internal.get_file_struct_by_index(pointer)?;
+            entry_names.push(entry.name);
+            pointer = entry.next;
+        }
+
+        if dir.is_directory {
+            Ok(DirIterator {
+                cursor: 0,
+                internal: Arc::downgrade(&self.internal),
+                path: path.to_owned(),
+                entry_names,
+            })
+        } else {
+            Err(Error::DirectoryNotFound)
+        }
+    }
+
+    pub fn open_entry(&self, file: DirEntry) -> Result<File, Error> {
+        self.open_file(&file.path)
+    }
+
+    pub fn open_file(&self, path: &str) -> Result<File, Error> {
+        let internal = self.internal.read();
+        let temp = internal.get_file_struct_by_path(path);
+
+        if temp.is_err() {
+            return Err(Error::FileNotFound);
+        }
+
+        let (index, file) = temp.unwrap();
+
+        if !file.is_directory {
+            Ok(File {
+                file_index: index,
+                cursor: 0,
+                internal: Arc::downgrade(&self.internal),
+                path: path.to_owned(),
+                readable: true,
+                writable: !internal.read_only,
+                append: false,
+            })
+        } else {
+            Err(Error::FileNotFound)
+        }
+    }
+
+    /// Options for opening files inside the image, modelled on [`std::fs::OpenOptions`].
+    pub fn options(&self) -> OpenOptions<'_> {
+        OpenOptions {
+            vfs: self,
+            read: false,
+            write: false,
+            append: false,
+            truncate: false,
+            create: false,
+            create_new: false,
+        }
+    }
+
+    /// Runs `f` as a single transaction: either every change it makes reaches the image or none does,
+    /// even if the process dies halfway. Returning an error from `f` rolls everything back.
+    /// A transaction started inside another one becomes part of the outer one.
+    pub fn transaction<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&Vfs) -> Result<T, E>,
+        E: From<Error>,
+    {
+        let is_nested = {
+            let mut owner = self.internal.wait_for_transaction();
+            owner.replace(thread::current().id()).is_some()
+        };
+        if is_nested {
+            return f(self);
+        }
+
+        let _guard = TransactionGuard {
+            internal: &self.internal,
+        };
+        self.internal.write().begin_transaction()?;
+
+        let value = f(self)?;
+        self.internal.write().commit_transaction()?;
+        Ok(value)
+    }
+
+    /// Moves the entry at `from` to `to`, keeping its contents and creation time.
+    /// Fails with [`Error::NameAlreadyInUse`] if `to` already exists.
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), Error> {
+        self.internal.write().rename_entity(from, to, false)
+    }
+
+    /// Like [`Vfs::rename`], but replaces an existing file or empty directory at `to`.
+    pub fn rename_overwrite(&self, from: &str, to: &str) -> Result<(), Error> {
+        self.internal.write().rename_entity(from, to, true)
+    }
+
+    /// Makes sure everything written to the image so far is on disk.
+    pub fn sync(&self) -> Result<(), Error> {
+        self.internal.read().storage.write().unwrap().sync_all()?;
+        Ok(())
+    }
+
+    /// How full the image is, counted from the allocation table and the file table.
+    pub fn stats(&self) -> Result<VfsStats, Error> {
+        self.internal.read().get_stats()
+    }
+
+    /// Every page range of the image with whatever it belongs to, the free runs in between and how
+    /// fragmented the free space is.
+    pub fn layout_report(&self) -> Result<LayoutReport, Error> {
+        self.internal.read().get_layout_report()
+    }
+
+    /// Moves every file into a single run of pages as close to the start of the image as it fits,
+    /// after moving the file and allocation tables forward, and cuts the image down to what is left.
+    /// Each move is a commit of its own, other threads get their turn in between.
+    pub fn defragment(&self) -> Result<(), Error> {
+        self.defragment_with_progress(|_| {})
+    }
+
+    /// [`Vfs::defragment`], calling `progress` whenever a table or file has been dealt with.
+    pub fn defragment_with_progress(&self, progress: impl FnMut(Progress)) -> Result<(), Error> {
+        self.reorganize(true, progress)
+    }
+
+    /// Moves the file and allocation tables and then the files as close to the start of the image as
+    /// they fit and cuts the image down to what is left. Unlike [`Vfs::defragment`] a file may be
+    /// split to fill the free runs before it, the point is the smallest image.
+    pub fn compact(&self) -> Result<(), Error> {
+        self.compact_with_progress(|_| {})
+    }
+
+    /// [`Vfs::compact`], calling `progress` whenever a table or file has been dealt with.
+    pub fn compact_with_progress(&self, progress: impl FnMut(Progress)) -> Result<(), Error> {
+        self.reorganize(false, progress)
+    }
+
+    fn reorganize(
+        &self,
+        defragment: bool,
+        mut progress: impl FnMut(Progress),
+    ) -> Result<(), Error> {
+        let mut files: Vec<(u64, u64, u64)> = Vec::new();
+        {
+            let internal = self.internal.read();
+            internal.check_writable()?;
+            for index in internal.get_file_slots() {
+                let file = internal.get_file_struct_by_index(index)?;
+                if !file.is_active {
+                    continue;
+                }
+                let pages = internal.get_file_pages(&file)?;
+                if let (Some(&first), Some(&last)) = (pages.first(), pages.last()) {
+                    files.push((index, first, last));
+                }
+            }
+        }
+        //defragmenting fills the image from the front, compacting empties it from the back
+        if defragment {
+            files.sort_by_key(|&(_, first, _)| first);
+        } else {
+            files.sort_by_key(|&(_, _, last)| std::cmp::Reverse(last));
+        }
+
+        let mut report = Progress {
+            done: 0,
+            total: files.len() as u64 + 1,
+            pages_moved: 0,
+        };
+        report.pages_moved += self.internal.write().move_system_tables()?;
+        report.done += 1;
+        progress(report);
+
+        for (index, _, _) in files {
+            report.pages_moved += self.internal.write().reorganize_file(index, defragment)?;
+            report.done += 1;
+            progress(report);
+        }
+
+        Ok(())
+    }
+
+    pub fn delete(&self, path: &str) -> Result<(), Error> {
+        if path.is_empty() {
+            return Ok(());
+        }
+        let mut path_componenets = path.split('/');
+
+        let name = path_componenets.next_back().unwrap();
+
+        let mut internal = self.internal.write();
+        internal.check_writable()?;
+
+        let mut previous_pointer = 0;
+        let mut child_entity = internal.get_file_struct_by_index(previous_pointer)?;
+        let mut current_pointer = child_entity.contents;
+
+        for path_dir in path_componenets {
+            if path_dir.is_empty() {
+                continue;
+            }
+
+            let mut found = false;
+
+            while current_pointer != 0 {
+                child_entity = internal.get_file_struct_by_index(current_pointer)?;
+
+                if child_entity.name == path_dir && child_entity.is_active {
+                    if child_entity.is_directory {
+                        previous_pointer = current_pointer;
+                        current_pointer = child_entity.contents;
+                        found = true;
+                        break;
+                    } else {
+                        return Err(Error::DirectoryNotFound);
+                    }
+                }
+
+                current_pointer = child_entity.next;
+            }
+
+            if !found {
+                return Err(Error::DirectoryNotFound);
+            }
+        }
+
+        //previous_pointer contains parent directory of where we want to create the entity
+        //current_pointer contains first entity in parent directory
+
+        let mut found = false;
+
+        while current_pointer != 0 {
+            child_entity = internal.get_file_struct_by_index(current_pointer)?;
+
+            if child_entity.name == name && child_entity.is_active {
+                found = true;
+                break;
+            }
+
+            current_pointer = child_entity.next;
+        }
+
+        if !found {
+            return Err(Error::FileNotFound);
+        }
+
+        internal.delete_entity(previous_pointer, current_pointer)?;
+
+        Ok(())
+    }
+}
+
+/// Geometry of a new image, see [`Vfs::create_with`].
+#[derive(Debug, Clone)]
+pub struct VfsOptions {
+    page_size: u64,
+    initial_file_table_pages: u64,
+    max_size: Option<u64>,
+    durability: Durability,
+    allocation_policy: AllocationPolicy,
+}
+
+impl Default for VfsOptions {
+    fn default() -> Self {
+        VfsOptions {
+            page_size: DEFAULT_PAGE_SIZE,
+            initial_file_table_pages: 1,
+            max_size: None,
+            durability: Durability::default(),
+            allocation_policy: AllocationPolicy::default(),
+        }
+    }
+}
+
+impl VfsOptions {
+    pub fn new() -> VfsOptions {
+        VfsOptions::default()
+    }
+
+    /// Size of a page in bytes, a power of two from 512 up to 1 MiB. Defaults to 4096.
+    pub fn page_size(&mut self, page_size: u64) -> &mut VfsOptions {
+        self.page_size = page_size;
+        self
+    }
+
+    /// Number of pages the file table starts with, it still grows once they are full. Defaults to 1.
+    pub fn initial_file_table_pages(&mut self, pages: u64) -> &mut VfsOptions {
+        self.initial_file_table_pages = pages;
+        self
+    }
+
+    /// Size in bytes the image is never allowed to grow past. Unlimited by default.
+    pub fn max_size(&mut self, max_size: u64) -> &mut VfsOptions {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    fn get_max_pages(&self) -> u64 {
+        self.max_size
+            .map_or(u64::MAX, |max_size| max_size / self.page_size)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let system_pages = DEFAULT_FILE_TABLE_PAGE + self.initial_file_table_pages;
+        if !self.page_size.is_power_of_two()
+            || self.page_size < MIN_PAGE_SIZE
+            || self.page_size > MAX_PAGE_SIZE
+            || self.initial_file_table_pages == 0
+            || system_pages > self.page_size * 8
+            || system_pages > self.get_max_pages()
+        {
+            return Err(Error::InvalidOptions);
+        }
+        Ok(())
+    }
+
+    /// How hard commits try to reach the disk, see [`Durability`]. Not stored in the image.
+    pub fn durability(&mut self, durability: Durability) -> &mut VfsOptions {
+        self.durability = durability;
+        self
+    }
+
+    /// Where file pages go and how many are reserved ahead, see [`AllocationPolicy`].
+    /// Not stored in the image.
+    pub fn allocation_policy(&mut self, policy: AllocationPolicy) -> &mut VfsOptions {
+        self.allocation_policy = policy;
+        self
+    }
+}
+
+/// How pages are handed out to files, see [`VfsOptions::allocation_policy`] and
+/// [`Vfs::set_allocation_policy`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllocationPolicy {
+    pub placement: Placement,
+    pub growth: Growth,
+}
+
+/// Which free run a file gets when it can't simply keep going where its last page is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Placement {
+    /// The first run that is big enough.
+    #[default]
+    FirstFit,
+    /// The smallest run that is big enough, keeping the large ones for large files.
+    BestFit,
+    /// The first run that is big enough after the last one handed out, wrapping around at the end.
+    NextFit,
+}
+
+/// How many pages a file that grows past its last page gets, the ones past its size are its
+/// spare capacity until it is truncated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Growth {
+    /// Just the pages the write needs.
+    #[default]
+    Exact,
+    /// Half as many pages again as the file had, or what the write needs if that is more.
+    OneAndAHalf,
+    /// Twice as many pages as the file had, or what the write needs if that is more.
+    Double,
+}
+
+impl Growth {
+    /// Number of pages a file that had `pages` pages and needs `needed` should end up with.
+    fn grow(self, pages: u64, needed: u64) -> u64 {
+        let target = match self {
+            Growth::Exact => needed,
+            Growth::OneAndAHalf => pages.saturating_add(pages / 2),
+            Growth::Double => pages.saturating_mul(2),
+        };
+        target.max(needed)
+    }
+}
+
+/// When commits call `sync_data` on the host file.
+///
+/// Each sync waits for the disk. With [`Durability::MetadataOnly`] a write that only adds to a file
+/// costs two of them, and one that overwrites bytes the file already has costs four, as it stages
+/// the changed pages past the end of the image and copies them in place with a commit of its own.
+/// [`Durability::Full`] adds one more to both. Images opened without [`Vfs::open_with`] or
+/// [`VfsOptions::durability`] start out with the default, [`Vfs::set_durability`] changes it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Durability {
+    /// Never, the operating system decides when things reach the disk. A power loss may leave the
+    /// image broken.
+    None,
+    /// Around putting metadata in place, so the image always comes back consistent. File contents
+    /// written right before a power loss may come back stale.
+    #[default]
+    MetadataOnly,
+    /// Also before the journal is written, so file contents are on disk before anything points at them.
+    Full,
+}
+
+/// Where the bytes of an image are kept, see [`Vfs::with_storage`].
+pub trait Storage: Send + Sync {
+    /// Fills all of `buf` with the bytes starting at `offset`. Readers may call it side by side.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<()>;
+
+    /// Writes all of `buf` starting at `offset`, growing the storage if it ends before that.
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> std::io::Result<()>;
+
+    fn len(&self) -> std::io::Result<u64>;
+
+    fn is_empty(&self) -> std::io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Grows the storage with zeros or cuts it short.
+    fn set_len(&mut self, length: u64) -> std::io::Result<()>;
+
+    /// Hands buffered writes on, without waiting for them to reach the disk.
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Waits until everything written so far is on disk.
+    fn sync_data(&mut self) -> std::io::Result<()>;
+
+    /// Like [`Storage::sync_data`], but also for metadata the image doesn't need.
+    fn sync_all(&mut self) -> std::io::Result<()> {
+        self.sync_data()
+    }
+
+    /// Storage that can't be written to. An image on it is opened read-only and is never repaired.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+/// Keeps the image in a file on the host.
+#[derive(Debug)]
+pub struct FileStorage {
+    file: fs::File,
+}
+
+impl FileStorage {
+    /// Opens an existing file for reading and writing.
+    pub fn open(filename: &str) -> Result<FileStorage, Error> {
+        let file = fs::File::options().read(true).write(true).open(filename)?;
+        Ok(FileStorage { file })
+    }
+
+    /// Creates a new file, failing if something already exists at `filename`.
+    pub fn create_new(filename: &str) -> Result<FileStorage, Error> {
+        let file = fs::File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(filename)?;
+        Ok(FileStorage { file })
+    }
+}
+
+impl From<fs::File> for FileStorage {
+    fn from(file: fs::File) -> Self {
+        FileStorage { file }
+    }
+}
+
+impl Storage for FileStorage {
+    #[cfg(unix)]
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(&self.file, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        let mut position = 0;
+        while position < buf.len() {
+            let read = std::os::windows::fs::FileExt::seek_read(
+                &self.file,
+                &mut buf[position..],
+                offset + position as u64,
+            )?;
+            if read == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            position += read;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(buf)
+    }
+
+    fn len(&self) -> std::io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn set_len(&mut self, length: u64) -> std::io::Result<()> {
+        self.file.set_len(length)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn sync_all(&mut self) -> std::io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+/// Keeps the image in memory, it is gone once the [`Vfs`] is dropped.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStorage {
+    bytes: Vec<u8>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+}
+
+impl From<Vec<u8>> for MemoryStorage {
+    fn from(bytes: Vec<u8>) -> Self {
+        MemoryStorage { bytes }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        let bytes = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| self.bytes.get(offset..(offset.checked_add(buf.len())?)))
+            .ok_or(std::io::ErrorKind::UnexpectedEof)?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> std::io::Result<()> {
+        let offset = usize::try_from(offset).map_err(|_| std::io::ErrorKind::OutOfMemory)?;
+        let end = offset + buf.len();
+        if self.bytes.len() < end {
+            self.bytes.resize(end, 0);
+        }
+        self.bytes[offset..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn len(&self) -> std::io::Result<u64> {
+        Ok(self.bytes.len() as u64)
+    }
+
+    fn set_len(&mut self, length: u64) -> std::io::Result<()> {
+        let length = usize::try_from(length).map_err(|_| std::io::ErrorKind::OutOfMemory)?;
+        self.bytes.resize(length, 0);
+        Ok(())
+    }
+
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps an image that is part of the binary, it can only be read.
+#[derive(Debug, Clone, Copy)]
+pub struct StaticStorage {
+    bytes: &'static [u8],
+}
+
+impl From<&'static [u8]> for StaticStorage {
+    fn from(bytes: &'static [u8]) -> Self {
+        StaticStorage { bytes }
+    }
+}
+
+impl Storage for StaticStorage {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        let bytes = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| self.bytes.get(offset..(offset.checked_add(buf.len())?)))
+            .ok_or(std::io::ErrorKind::UnexpectedEof)?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write_at(&mut self, _offset: u64, _buf: &[u8]) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::ReadOnlyFilesystem.into())
+    }
+
+    fn len(&self) -> std::io::Result<u64> {
+        Ok(self.bytes.len() as u64)
+    }
+
+    fn set_len(&mut self, _length: u64) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::ReadOnlyFilesystem.into())
+    }
+
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+}
+
+struct VfsInternal {
+    storage: RwLock<Box<dyn Storage>>,
+    page_size: u64,
+    file_table_page: u64,
+    file_table_size: u64,
+    allocation_table_page: u64,
+    allocation_table_size: u64,
+    max_pages: u64,
+    transaction: Mutex<Option<TransactionState>>,
+    //whether `transaction` is set, so reads outside of one don't have to take its lock
+    in_transaction: AtomicBool,
+    journal_sequence: AtomicU64,
+    recovery: JournalRecovery,
+    durability: Durability,
+    allocation_policy: AllocationPolicy,
+    //where the last run of file pages ended, next fit carries on from there
+    next_fit_cursor: AtomicU64,
+    read_only: bool,
+}
+
+/// What opening an image did with the journal left behind by the last commit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalRecovery {
+    /// The last commit went through, there was nothing to do.
+    Clean,
+    /// The last commit was interrupted after its journal was complete, it was applied.
+    Replayed,
+    /// The last commit was interrupted while writing its journal, it was thrown away.
+    Discarded,
+}
+
+/// How full an image is, see [`Vfs::stats`]. Without a maximum size the pages are those of the image
+/// as it is now, it grows once they run out. The file table grows as well when it is out of slots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VfsStats {
+    pub page_size: u64,
+    pub total_pages: u64,
+    /// Pages taken by files and by the image itself.
+    pub used_pages: u64,
+    pub free_pages: u64,
+    /// Free pages in a row, the most a file can get without being split.
+    pub largest_free_run: u64,
+    /// Slots of the file table taken by files and directories, the root directory included.
+    pub file_slots_used: u64,
+    pub file_slots_free: u64,
+}
+
+/// Where everything is in an image, see [`Vfs::layout_report`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayoutReport {
+    pub page_size: u64,
+    /// Allocated pages in order, runs of pages with the same owner are joined.
+    pub regions: Vec<PageRegion>,
+    /// Free pages before the end of the image.
+    pub free_runs: Vec<std::ops::Range<u64>>,
+    /// How much of the free space is outside of the largest free run, from 0 when it is all in one
+    /// piece or there is none, towards 1 when it is scattered over many small runs.
+    pub fragmentation: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageRegion {
+    pub pages: std::ops::Range<u64>,
+    pub owner: PageOwner,
+}
+
+/// What an allocated page is used for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PageOwner {
+    SystemHeader,
+    AllocationTable,
+    Journal,
+    FileTable,
+    /// Contents of the file at the path.
+    File(String),
+    /// Extent map of the file at the path.
+    ExtentMap(String),
+    /// Marked as allocated without anything using it.
+    Unknown,
+}
+
+/// How far [`Vfs::defragment_with_progress`] or [`Vfs::compact_with_progress`] got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Files dealt with so far, the system tables count as one.
+    pub done: u64,
+    pub total: u64,
+    /// Pages that ended up somewhere else so far.
+    pub pages_moved: u64,
+}
+
+/// Everything a running [`Vfs::transaction`] changed. None of it reaches the image before the commit.
+struct TransactionState {
+    dirty_pages: BTreeMap<u64, Vec<u8>>,
+    pending: Vec<Modification>,
+    //length of the image as the transaction sees it, the physical file itself is left alone
+    length: u64,
+    original_length: u64,
+    //system header at the start, put back on rollback
+    original_header: [u64; 4],
+}
+
+impl VfsInternal {
+    fn open(storage: Box<dyn Storage>, durability: Durability) -> Result<VfsInternal, Error> {
+        let length = storage.len()?;
+
+        let mut header = [0u8; HEADER_SIZE];
+        if length < HEADER_SIZE as u64 {
+            return Err(Error::NotAVfsImage);
+        }
+        storage.read_at(0, &mut header)?;
+
+        if header[..MAGIC.len()] != MAGIC {
+            return Err(Error::NotAVfsImage);
+        }
+
+        let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let read_u64 =
+            |offset: usize| u64::from_le_bytes(header[offset..(offset + 8)].try_into().unwrap());
+
+        let page_size = read_u64(8);
+        let file_table_page = read_u64(16);
+        let file_table_size = read_u64(24);
+        let allocation_table_page = read_u64(32);
+        let allocation_table_size = read_u64(40);
+        //0 means the image may grow without bounds
+        let max_pages = match read_u64(48) {
+            0 => u64::MAX,
+            max_pages => max_pages,
+        };
+
+        if !page_size.is_power_of_two()
+            || !(MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size)
+            || length % page_size != 0
+        {
+            return Err(Error::NotAVfsImage);
+        }
+
+        //every system page has to be inside the image, otherwise it was cut short
+        let number_of_pages = length / page_size;
+        let is_inside = |page: u64, size: u64| {
+            size != 0
+                && page
+                    .checked_add(size)
+                    .is_some_and(|end| end <= number_of_pages)
+        };
+        if !is_inside(DEFAULT_SYSTEM_COMMIT_PAGE, 1)
+            || !is_inside(file_table_page, file_table_size)
+            || !is_inside(allocation_table_page, allocation_table_size)
+        {
+            return Err(Error::NotAVfsImage);
+        }
+
+        let read_only = storage.is_read_only();
+        let mut new_vfs = VfsInternal {
+            storage: RwLock::new(storage),
+            page_size,
+            file_table_page,
+            file_table_size,
+            allocation_table_page,
+            allocation_table_size,
+            max_pages,
+            transaction: Mutex::new(None),
+            in_transaction: AtomicBool::new(false),
+            journal_sequence: AtomicU64::new(0),
+            recovery: JournalRecovery::Clean,
+            durability,
+            allocation_policy: AllocationPolicy::default(),
+            next_fit_cursor: AtomicU64::new(0),
+            read_only,
+        };
+
+        new_vfs.recovery = if read_only {
+            //a torn journal never touched the image, but a complete one would have to be replayed
+            match new_vfs.read_journal()?.1 {
+                Some(modifications) if modifications.is_empty() => JournalRecovery::Clean,
+                Some(_) => return Err(Error::ReadOnly),
+                None => JournalRecovery::Discarded,
+            }
+        } else {
+            new_vfs.resolve_commit()?
+        };
+
+        Ok(new_vfs)
+    }
+
+    fn create(mut storage: Box<dyn Storage>, options: &VfsOptions) -> Result<VfsInternal, Error> {
+        options.validate()?;
+        let system_pages = DEFAULT_FILE_TABLE_PAGE + options.initial_file_table_pages;
+        let max_pages = options.get_max_pages();
+
+        //start from nothing and make room for header + allocation table + system commit + file table
+        storage.set_len(0)?;
+        storage.set_len(system_pages * options.page_size)?;
+
+        let new_vfs = VfsInternal {
+            storage: RwLock::new(storage),
+            page_size: options.page_size,
+            file_table_page: DEFAULT_FILE_TABLE_PAGE,
+            file_table_size: options.initial_file_table_pages,
+            allocation_table_page: DEFAULT_ALLOCATION_TABLE_PAGE,
+            allocation_table_size: 1,
+            max_pages,
+            transaction: Mutex::new(None),
+            in_transaction: AtomicBool::new(false),
+            journal_sequence: AtomicU64::new(0),
+            recovery: JournalRecovery::Clean,
+            durability: options.durability,
+            allocation_policy: options.allocation_policy,
+            next_fit_cursor: AtomicU64::new(0),
+            read_only: false,
+        };
+
+        {
+            let file_table_index = new_vfs.get_file_table_index();
+            let allocation_table_index = new_vfs.allocation_table_page * new_vfs.page_size;
+            let mut storage = new_vfs.storage.write().unwrap();
+
+            //write signature and format version where the nullptr would be, then page size
+            let mut header = Vec::new();
+            header.extend_from_slice(&MAGIC);
+            header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+            header.extend_from_slice(&new_vfs.page_size.to_le_bytes());
+
+            //write page number of file table
+            header.extend_from_slice(&new_vfs.file_table_page.to_le_bytes());
+            header.extend_from_slice(&new_vfs.file_table_size.to_le_bytes());
+
+            //write page number and size of allocation table
+            header.extend_from_slice(&new_vfs.allocation_table_page.to_le_bytes());
+            header.extend_from_slice(&new_vfs.allocation_table_size.to_le_bytes());
+
+            //write page limit
+            header.extend_from_slice(&options.max_size.map_or(0, |_| max_pages).to_le_bytes());
+            storage.write_at(0, &header)?;
+
+            //allocate system pages
+            let mut allocation_bytes = vec![0u8; ceil_div(system_pages, 8) as usize];
+            for page_number in 0..system_pages {
+                allocation_bytes[(page_number / 8) as usize] |= 1 << (page_number % 8);
+            }
+            storage.write_at(allocation_table_index, &allocation_bytes)?;
+
+            //write root in file table page
+            storage.write_at(file_table_index, &Self::make_root())?;
+            storage.flush()?;
+        }
+
+        Ok(new_vfs)
+    }
+
+    fn make_root() -> FileBytes {
+        let time = Self::get_system_time();
+        let root = FileStruct {
+            is_root: true,
+            is_active: true,
+            is_directory: true,
+            name: "root".to_owned(),
+            contents: 0,
+            next: 0,
+            size: 0,
+            creation_time: time,
+            last_write_time: time,
+        };
+        root.try_into().unwrap()
+    }
+
+    fn get_file_struct_by_index(&self, index: u64) -> Result<FileStruct, Error> {
+        if index + FILE_STRUCT_SIZE as u64 >= self.file_table_size * self.page_size {
+            return Ok([0; FILE_STRUCT_SIZE].into());
+        }
+        let mut file_struct_buffer = [0u8; FILE_STRUCT_SIZE];
+        self.read_bytes(
+            self.file_table_page * self.page_size + index,
+            &mut file_struct_buffer,
+        )?;
+        Ok(FileStruct::from(file_struct_buffer))
+    }
+
+    fn get_file_struct_by_path(&self, path: &str) -> Result<(u64, FileStruct), Error> {
+        let mut path_componenets = path.split('/');
+
+        let name = path_componenets.next_back().unwrap();
+
+        let mut child_entity = self.get_file_struct_by_index(0)?;
+        let mut current_pointer = child_entity.contents;
+
+        for path_dir in path_componenets {
+            if path_dir.is_empty() {
+                continue;
+            }
+
+            let mut found = false;
+
+            while current_pointer != 0 {
+                child_entity = self.get_file_struct_by_index(current_pointer)?;
+
+                if child_entity.name == path_dir && child_entity.is_active {
+                    if child_entity.is_directory {
+                        current_pointer = child_entity.contents;
+                        found = true;
+                        break;
+                    } else {
+                        return Err(Error::DirectoryNotFound);
+                    }
+                }
+
+                current_pointer = child_entity.next;
+            }
+
+            if !found {
+                return Err(Error::DirectoryNotFound);
+            }
+        }
+
+        while current_pointer != 0 {
+            child_entity = self.get_file_struct_by_index(current_pointer)?;
+
+            if child_entity.name == name && child_entity.is_active {
+                return Ok((current_pointer, child_entity));
+            }
+
+            current_pointer = child_entity.next;
+        }
+
+        Err(Error::FileNotFound)
+    }
+
+    /// Finds the directory holding `path`, returning its index and the name of the entry inside it.
+    fn get_parent_dir_by_path<'a>(&self, path: &'a str) -> Result<(u64, &'a str), Error> {
+        let mut path_componenets = path.split('/');
+
+        let name = path_componenets.next_back().unwrap();
+
+        let mut parent_pointer = 0;
+
+        for path_dir in path_componenets {
+            if path_dir.is_empty() {
+                continue;
+            }
+
+            let mut current_pointer = self.get_file_struct_by_index(parent_pointer)?.contents;
+
+            loop {
+                if current_pointer == 0 {
+                    return Err(Error::DirectoryNotFound);
+                }
+
+                let child_entity = self.get_file_struct_by_index(current_pointer)?;
+
+                if child_entity.name == path_dir && child_entity.is_active {
+                    if !child_entity.is_directory {
+                        return Err(Error::DirectoryNotFound);
+                    }
+                    parent_pointer = current_pointer;
+                    break;
+                }
+
+                current_pointer = child_entity.next;
+            }
+        }
+
+        Ok((parent_pointer, name))
+    }
+
+    fn find_child_by_name(&self, parent_index: u64, name: &str) -> Result<Option<u64>, Error> {
+        let mut current_pointer = self.get_file_struct_by_index(parent_index)?.contents;
+
+        while current_pointer != 0 {
+            let child_entity = self.get_file_struct_by_index(current_pointer)?;
+
+            if child_entity.name == name && child_entity.is_active {
+                return Ok(Some(current_pointer));
+            }
+
+            current_pointer = child_entity.next;
+        }
+
+        Ok(None)
+    }
+
+    fn is_in_subtree(&self, root_index: u64, index: u64) -> Result<bool, Error> {
+        if root_index == index {
+            return Ok(true);
+        }
+
+        let mut current_pointer = self.get_file_struct_by_index(root_index)?.contents;
+
+        while current_pointer != 0 {
+            let child_entity = self.get_file_struct_by_index(current_pointer)?;
+
+            if child_entity.is_directory && self.is_in_subtree(current_pointer, index)? {
+                return Ok(true);
+            }
+
+            current_pointer = child_entity.next;
+        }
+
+        Ok(false)
+    }
+
+    fn find_inactive_file_slot(&self) -> Result<Option<u64>, Error> {
+        let mut flags = [0u8; 1];
+        let mut index = 0;
+
+        self.read_bytes(self.get_file_table_index(), &mut flags)?;
+
+        while flags[0] & 0b10 != 0 {
+            //is active
+            index += FILE_STRUCT_SIZE as u64;
+
+            if index + FILE_STRUCT_SIZE as u64 >= self.file_table_size * self.page_size {
+                return Ok(None);
+            }
+
+            self.read_bytes(self.get_file_table_index() + index, &mut flags)?;
+        }
+
+        Ok(Some(index))
+    }
+
+    fn get_system_time() -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            // .as_millis() as u64
+            .as_secs()
+    }
+
+    fn get_file_table_index(&self) -> u64 {
+        self.page_size * self.file_table_page
+    }
+
+    fn print_tree_recursive(&self, from_index: u64, depth: u64) -> Result<(), Error> {
+        let mut pointer = from_index;
+        let mut entity: FileStruct;
+        while pointer != 0 {
+            entity = self.get_file_struct_by_index(pointer)?;
+
+            if entity.is_active {
+                print!("{}", "---".repeat(depth as usize));
+                println!(
+                    "{} : {}",
+                    entity.name,
+                    if entity.is_directory {
+                        "directory"
+                    } else {
+                        "file"
+                    }
+                );
+
+                if entity.is_directory {
+                    self.print_tree_recursive(entity.contents, depth + 1)?;
+                }
+            }
+            pointer = entity.next;
+        }
+        Ok(())
+    }
+
+    fn get_allocation_table_capacity(&self) -> u64 {
+        self.allocation_table_size * self.page_size * 8
+    }
+
+    fn read_allocation_table(&self) -> Result<Vec<u8>, Error> {
+        let mut table = vec![0u8; (self.allocation_table_size * self.page_size) as usize];
+        self.read_bytes(self.allocation_table_page * self.page_size, &mut table)?;
+        Ok(table)
+    }
+
+    fn allocate_page_range(
+        &self,
+        page_range: std::ops::Range<u64>,
+        is_allocated: bool,
+    ) -> Result<(), Error> {
+        if page_range.end > self.get_allocation_table_capacity() {
+            return Err(Error::PageNumberTooBig);
+        }
+
+        if page_range.is_empty() {
+            return Ok(());
+        }
+
+        let first_byte = page_range.start / 8;
+        let last_byte = (page_range.end - 1) / 8;
+        let table_index = self.allocation_table_page * self.page_size;
+
+        let mut bytes = vec![0u8; (last_byte - first_byte + 1) as usize];
+        self.read_bytes(table_index + first_byte, &mut bytes)?;
+
+        for page_number in page_range {
+            let byte = &mut bytes[(page_number / 8 - first_byte) as usize];
+            let bit_location = page_number % 8;
+            *byte = if is_allocated {
+                *byte | 1 << bit_location
+            } else {
+                *byte & !(1 << bit_location)
+            };
+        }
+
+        self.write_bytes(table_index + first_byte, &bytes)?;
+
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn is_page_allocated(&self, page_number: u64) -> Result<bool, Error> {
+        //pages past the end of the allocation table have never been handed out
+        if page_number >= self.get_allocation_table_capacity() {
+            return Ok(false);
+        }
+
+        let byte_location = page_number / 8;
+        let bit_location = page_number % 8;
+
+        let mut byte = [0; 1];
+        self.read_bytes(
+            self.page_size * self.allocation_table_page + byte_location,
+            &mut byte,
+        )?;
+
+        Ok(byte[0] & 1 << bit_location != 0)
+    }
+
+    fn find_first_fitting_page_range(
+        &self,
+        number_of_pages_needed: u64,
+        reallocating_pages: std::ops::Range<u64>,
+    ) -> Result<Option<u64>, Error> {
+        let table = self.read_allocation_table()?;
+        let capacity = self.get_allocation_table_capacity();
+
+        let mut i = 0;
+        let mut is_potential_range = false;
+        let mut potential_range_position = 0;
+        let mut potential_range_length = 0;
+        while i < capacity {
+            let is_current_page_allocated =
+                table[(i / 8) as usize] & 1 << (i % 8) != 0 && !reallocating_pages.contains(&i);
+            if is_potential_range {
+                if is_current_page_allocated {
+                    is_potential_range = false;
+                } else {
+                    potential_range_length += 1;
+                }
+            } else if !is_current_page_allocated {
+                is_potential_range = true;
+                potential_range_length = 1;
+                potential_range_position = i;
+            }
+
+            if is_potential_range && potential_range_length == number_of_pages_needed {
+                break;
+            }
+
+            i += 1;
+        }
+
+        //pages past the end of the allocation table are free, so the last run keeps going
+        if !is_potential_range {
+            potential_range_position = capacity;
+        }
+
+        if potential_range_position + number_of_pages_needed <= self.max_pages {
+            Ok(Some(potential_range_position))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Finds a free run of pages, growing the allocation table until it covers the run.
+    fn find_free_page_range(
+        &mut self,
+        number_of_pages_needed: u64,
+        reallocating_pages: std::ops::Range<u64>,
+    ) -> Result<u64, Error> {
+        loop {
+            let location = self
+                .find_first_fitting_page_range(number_of_pages_needed, reallocating_pages.clone())?
+                .ok_or(Error::IO(std::io::ErrorKind::OutOfMemory))?;
+
+            if location + number_of_pages_needed <= self.get_allocation_table_capacity() {
+                return Ok(location);
+            }
+
+            self.grow_allocation_table(location + number_of_pages_needed)?;
+        }
+    }
+
+    fn grow_allocation_table(&mut self, number_of_pages: u64) -> Result<(), Error> {
+        let mut new_size = self.allocation_table_size;
+        let new_location = loop {
+            while new_size * self.page_size * 8 < number_of_pages {
+                new_size *= 2;
+            }
+
+            let location = self
+                .find_first_fitting_page_range(new_size, 0..0)?
+                .ok_or(Error::IO(std::io::ErrorKind::OutOfMemory))?;
+
+            //the new table has to keep track of its own pages as well
+            if location + new_size <= new_size * self.page_size * 8 {
+                break location;
+            }
+            new_size *= 2;
+        };
+
+        let mut table = self.read_allocation_table()?;
+        table.resize((new_size * self.page_size) as usize, 0);
+        for page_number in new_location..(new_location + new_size) {
+            table[(page_number / 8) as usize] |= 1 << (page_number % 8);
+        }
+
+        //critical
+        self.ensure_length(new_location + new_size)?;
+        self.write_bytes(new_location * self.page_size, &table)?;
+        //critical
+
+        self.sync_before_commit()?;
+
+        let modifications = vec![
+            Modification::AllocationTableHeader(new_location, new_size),
+            Modification::AllcationTable(
+                self.allocation_table_page
+                    ..(self.allocation_table_page + self.allocation_table_size),
+                false,
+            ),
+        ];
+        self.schedule_commit(modifications)?;
+        self.resolve_commit()?;
+
+        Ok(())
+    }
+
+    fn get_number_of_pages(&self) -> Result<u64, Error> {
+        let table = self.read_allocation_table()?;
+        match table.iter().rposition(|&byte| byte != 0) {
+            Some(i) => Ok(i as u64 * 8 + (8 - table[i].leading_zeros() as u64)),
+            None => Ok(1),
+        }
+    }
+
+    /// Collects free pages, preferring to continue right at `preferred_start` and then a single free
+    /// run. Only when the free space is too fragmented for that are several smaller runs returned.
+    /// The runs may reach past the end of the allocation table.
+    fn find_free_page_runs(
+        &self,
+        number_of_pages_needed: u64,
+        preferred_start: u64,
+    ) -> Result<Option<Vec<std::ops::Range<u64>>>, Error> {
+        let table = self.read_allocation_table()?;
+        let limit = self.get_allocation_table_capacity().min(self.max_pages);
+        let is_free =
+            |page: u64| page >= limit || table[(page / 8) as usize] & 1 << (page % 8) == 0;
+
+        let mut runs: Vec<std::ops::Range<u64>> = Vec::new();
+        let mut remaining = number_of_pages_needed;
+
+        if preferred_start != 0 {
+            let mut end = preferred_start;
+            while end - preferred_start < remaining && end < self.max_pages && is_free(end) {
+                end += 1;
+            }
+            if end > preferred_start {
+                runs.push(preferred_start..end);
+                remaining -= end - preferred_start;
+            }
+        }
+
+        if remaining == 0 {
+            return Ok(Some(runs));
+        }
+
+        let is_available = |page: u64| is_free(page) && !runs.iter().any(|run| run.contains(&page));
+
+        let mut free_runs = Vec::new();
+        let mut run_start = None;
+        for page in 0..limit {
+            if !is_available(page) {
+                if let Some(start) = run_start.take() {
+                    free_runs.push(start..page);
+                }
+            } else if run_start.is_none() {
+                run_start = Some(page);
+            }
+        }
+
+        //pages past the end of the allocation table are free, so the last run keeps going
+        let tail_start = run_start
+            .unwrap_or(limit)
+            .max(runs.last().map_or(0, |run| run.end));
+        free_runs.push(tail_start..self.max_pages);
+        free_runs.retain(|run| !run.is_empty());
+
+        let fits = |run: &&std::ops::Range<u64>| run.end - run.start >= remaining;
+        let fitting_run = match self.allocation_policy.placement {
+            Placement::FirstFit => free_runs.iter().find(fits).cloned(),
+            Placement::BestFit => free_runs
+                .iter()
+                .filter(fits)
+                .min_by_key(|run| run.end - run.start)
+                .cloned(),
+            Placement::NextFit => {
+                let cursor = self.next_fit_cursor.load(Ordering::Relaxed);
+                free_runs
+                    .iter()
+                    .map(|run| run.start.max(cursor)..run.end)
+                    .find(|run| run.start < run.end && run.end - run.start >= remaining)
+                    .or_else(|| free_runs.iter().find(fits).cloned())
+            }
+        };
+        if let Some(run) = fitting_run {
+            runs.push(run.start..(run.start + remaining));
+            return Ok(Some(runs));
+        }
+
+        for run in free_runs {
+            let length = remaining.min(run.end - run.start);
+            runs.push(run.start..(run.start + length));
+            remaining -= length;
+            if remaining == 0 {
+                return Ok(Some(runs));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn ensure_length(&self, number_of_pages: u64) -> Result<(), Error> {
+        if self.get_physical_len()? < number_of_pages * self.page_size {
+            self.set_physical_len(number_of_pages * self.page_size)?;
+        }
+        Ok(())
+    }
+
+    fn get_extents_per_page(&self) -> usize {
+        (self.page_size as usize - 2 * size_of::<u64>()) / EXTENT_SIZE
+    }
+
+    fn get_extent_page(&self, page_number: u64) -> Result<ExtentPage, Error> {
+        let mut page = vec![0u8; self.page_size as usize];
+        self.read_bytes(page_number * self.page_size, &mut page)?;
+        Ok(ExtentPage::from(page.as_slice()))
+    }
+
+    fn update_extent_page(&self, page_number: u64, extent_page: ExtentPage) -> Result<(), Error> {
+        let bytes: Vec<u8> = extent_page.into();
+        self.write_bytes(page_number * self.page_size, &bytes)
+    }
+
+    /// Pages of the extent map of a file, in order, together with their contents.
+    fn get_extent_map(&self, file: &FileStruct) -> Result<Vec<(u64, ExtentPage)>, Error> {
+        let mut extent_map = Vec::new();
+        let mut pointer = file.contents;
+        while pointer != 0 {
+            let extent_page = self.get_extent_page(pointer)?;
+            let next = extent_page.next;
+            extent_map.push((pointer, extent_page));
+            pointer = next;
+        }
+        Ok(extent_map)
+    }
+
+    fn get_extents(&self, file: &FileStruct) -> Result<Vec<std::ops::Range<u64>>, Error> {
+        Ok(self
+            .get_extent_map(file)?
+            .into_iter()
+            .flat_map(|(_, extent_page)| extent_page.extents)
+            .collect())
+    }
+
+    /// Gives the pages `pages` of a file physical pages where they are holes or past its last page,
+    /// without moving the ones it already has.
+    /// `extents` is updated in place, the returned modifications still have to be committed.
+    fn allocate_file_pages(
+        &mut self,
+        file: &mut FileStruct,
+        extents: &mut Vec<std::ops::Range<u64>>,
+        pages: std::ops::Range<u64>,
+    ) -> Result<Vec<Modification>, Error> {
+        let number_of_pages = count_hole_pages(extents, pages.clone());
+        if number_of_pages == 0 {
+            return Ok(Vec::new());
+        }
+
+        let extent_map = self.get_extent_map(file)?;
+        let extents_per_page = self.get_extents_per_page() as u64;
+        let preferred_start = extents
+            .iter()
+            .rfind(|extent| !is_hole(extent))
+            .map_or(0, |extent| extent.end);
+
+        let mut extra_map_pages = 0;
+        let (runs, new_extents, map_pages) = loop {
+            let runs = self
+                .find_free_page_runs(number_of_pages + extra_map_pages, preferred_start)?
+                .ok_or(Error::IO(std::io::ErrorKind::OutOfMemory))?;
+
+            let end = runs.iter().map(|run| run.end).max().unwrap_or(0);
+            if end > self.get_allocation_table_capacity() {
+                self.grow_allocation_table(end)?;
+                continue;
+            }
+
+            //the first pages hold the data, whatever is left over extends the extent map
+            let mut data_runs = Vec::new();
+            let mut map_pages: Vec<u64> = extent_map.iter().map(|(page, _)| *page).collect();
+            let mut data_left = number_of_pages;
+            for run in &runs {
+                let data_end = run.start + data_left.min(run.end - run.start);
+                if data_end > run.start {
+                    data_runs.push(run.start..data_end);
+                    data_left -= data_end - run.start;
+                }
+                map_pages.extend(data_end..run.end);
+            }
+            let new_extents = fill_holes(extents, pages.clone(), data_runs);
+
+            let map_pages_needed = ceil_div(new_extents.len() as u64, extents_per_page);
+            if map_pages_needed > map_pages.len() as u64 {
+                extra_map_pages += map_pages_needed - map_pages.len() as u64;
+                continue;
+            }
+
+            break (runs, new_extents, map_pages);
+        };
+
+        let mut modifications = Vec::new();
+        for run in &runs {
+            modifications.push(Modification::AllcationTable(run.clone(), true));
+        }
+
+        modifications.append(&mut self.update_extent_map(&extent_map, &new_extents, &map_pages));
+
+        self.ensure_length(runs.iter().map(|run| run.end).max().unwrap_or(0))?;
+        if let Some(run) = runs.last() {
+            self.next_fit_cursor.store(run.end, Ordering::Relaxed);
+        }
+
+        file.contents = map_pages[0];
+        *extents = new_extents;
+
+        Ok(modifications)
+    }
+
+    /// Like [`VfsInternal::allocate_file_pages`], but a file that grows past its last page also
+    /// gets the spare pages the allocation policy asks for, as long as there is room for them.
+    fn grow_file_pages(
+        &mut self,
+        file: &mut FileStruct,
+        extents: &mut Vec<std::ops::Range<u64>>,
+        pages: std::ops::Range<u64>,
+    ) -> Result<Vec<Modification>, Error> {
+        let file_pages: u64 = extents.iter().map(|extent| extent.end - extent.start).sum();
+        let target = self.allocation_policy.growth.grow(file_pages, pages.end);
+        if pages.end > file_pages && target > pages.end {
+            match self.allocate_file_pages(file, extents, pages.start..target) {
+                Err(Error::IO(std::io::ErrorKind::OutOfMemory)) => {}
+                result => return result,
+            }
+        }
+        self.allocate_file_pages(file, extents, pages)
+    }
+
+    /// Drops everything past the first `number_of_pages` pages of a file, releasing the pages
+    /// and whatever part of the extent map is no longer needed.
+    fn shrink_file(
+        &self,
+        file: &mut FileStruct,
+        number_of_pages: u64,
+    ) -> Result<Vec<Modification>, Error> {
+        let extent_map = self.get_extent_map(file)?;
+
+        let mut kept_extents = Vec::new();
+        let mut released = Vec::new();
+        let mut remaining = number_of_pages;
+        for extent in extent_map
+            .iter()
+            .flat_map(|(_, extent_page)| extent_page.extents.iter().cloned())
+        {
+            let kept = remaining.min(extent.end - extent.start);
+            if is_hole(&extent) {
+                push_extent(&mut kept_extents, hole(kept));
+            } else {
+                push_extent(&mut kept_extents, extent.start..(extent.start + kept));
+                if extent.start + kept < extent.end {
+                    released.push((extent.start + kept)..extent.end);
+                }
+            }
+            remaining -= kept;
+        }
+        //a hole at the end is implied by the size
+        if kept_extents.last().is_some_and(is_hole) {
+            kept_extents.pop();
+        }
+
+        let map_pages: Vec<u64> = extent_map.iter().map(|(page, _)| *page).collect();
+        let map_pages_needed = ceil_div(
+            kept_extents.len() as u64,
+            self.get_extents_per_page() as u64,
+        ) as usize;
+        for &page_number in &map_pages[map_pages_needed..] {
+            released.push(page_number..(page_number + 1));
+        }
+
+        let mut modifications: Vec<Modification> = released
+            .into_iter()
+            .map(|range| Modification::AllcationTable(range, false))
+            .collect();
+        modifications.append(&mut self.update_extent_map(
+            &extent_map,
+            &kept_extents,
+            &map_pages[..map_pages_needed],
+        ));
+
+        file.contents = map_pages[..map_pages_needed].first().copied().unwrap_or(0);
+
+        Ok(modifications)
+    }
+
+    /// Lays `extents` out over `map_pages`, only returning records for the pages that change.
+    fn update_extent_map(
+        &self,
+        extent_map: &[(u64, ExtentPage)],
+        extents: &[std::ops::Range<u64>],
+        map_pages: &[u64],
+    ) -> Vec<Modification> {
+        let mut modifications = Vec::new();
+        let mut chunks = extents.chunks(self.get_extents_per_page());
+        for (i, &page_number) in map_pages.iter().enumerate() {
+            let extent_page = ExtentPage {
+                next: map_pages.get(i + 1).copied().unwrap_or(0),
+                extents: chunks.next().map_or(Vec::new(), |chunk| chunk.to_vec()),
+            };
+            if extent_map.get(i) != Some(&(page_number, extent_page.clone())) {
+                modifications.push(Modification::ExtentMap(page_number, extent_page));
+            }
+        }
+        modifications
+    }
+
+    /// Splits `length` bytes of a file starting at `offset` into contiguous pieces of the physical file.
+    /// Pieces without a physical offset are holes, or past the last page of the file.
+    fn map_file_range(
+        &self,
+        extents: &[std::ops::Range<u64>],
+        offset: u64,
+        length: u64,
+    ) -> Vec<(Option<u64>, u64)> {
+        let mut pieces = Vec::new();
+        let end = offset + length;
+        let mut extent_offset = 0;
+        for extent in extents {
+            if end <= extent_offset {
+                break;
+            }
+            let extent_end = extent_offset + (extent.end - extent.start) * self.page_size;
+            let from = offset.max(extent_offset);
+            let to = end.min(extent_end);
+            if from < to {
+                let physical_offset = (!is_hole(extent))
+                    .then(|| extent.start * self.page_size + from - extent_offset);
+                pieces.push((physical_offset, to - from));
+            }
+            extent_offset = extent_end;
+        }
+        let from = offset.max(extent_offset);
+        if from < end {
+            pieces.push((None, end - from));
+        }
+        pieces
+    }
+
+    fn read_file_data(
+        &self,
+        extents: &[std::ops::Range<u64>],
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        let mut position = 0;
+        for (physical_offset, length) in self.map_file_range(extents, offset, buf.len() as u64) {
+            let piece = &mut buf[position..(position + length as usize)];
+            match physical_offset {
+                Some(physical_offset) => self.read_bytes(physical_offset, piece)?,
+                None => piece.fill(0),
+            }
+            position += length as usize;
+        }
+        Ok(())
+    }
+
+    /// Zeroes the bytes of a file from `start` up to `end`. Holes are left alone, they read as zeros anyway.
+    fn zero_file_data(
+        &self,
+        extents: &[std::ops::Range<u64>],
+        start: u64,
+        end: u64,
+    ) -> Result<(), Error> {
+        if end <= start {
+            return Ok(());
+        }
+        let zeros = vec![0u8; self.page_size as usize];
+        for (physical_offset, length) in self.map_file_range(extents, start, end - start) {
+            let Some(physical_offset) = physical_offset else {
+                continue;
+            };
+            let mut written = 0;
+            while written < length {
+                let piece = (length - written).min(self.page_size);
+                self.write_bytes(physical_offset + written, &zeros[..piece as usize])?;
+                written += piece;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes to pages the file already has, see [`VfsInternal::allocate_file_pages`].
+    fn write_file_data(
+        &self,
+        extents: &[std::ops::Range<u64>],
+        offset: u64,
+        buf: &[u8],
+    ) -> Result<(), Error> {
+        let mut position = 0;
+        for (physical_offset, length) in self.map_file_range(extents, offset, buf.len() as u64) {
+            self.write_bytes(
+                physical_offset.unwrap(),
+                &buf[position..(position + length as usize)],
+            )?;
+            position += length as usize;
+        }
+        Ok(())
+    }
+
+    fn update_file_by_index(&self, index: u64, file: FileStruct) -> Result<(), Error> {
+        let bytes: FileBytes = file.try_into().unwrap();
+        self.write_bytes(self.get_file_table_index() + index, &bytes)
+    }
+
+    /// Reads from the image, seeing the changes of the running transaction if there is one.
+    fn read_bytes(&self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        if !self.is_in_transaction() {
+            self.storage.read().unwrap().read_at(offset, buf)?;
+            return Ok(());
+        }
+        let transaction = self.transaction.lock().unwrap();
+        let state = transaction.as_ref().unwrap();
+
+        let mut position = 0;
+        while position < buf.len() {
+            let page_number = (offset + position as u64) / self.page_size;
+            let page_offset = ((offset + position as u64) % self.page_size) as usize;
+            let length = (self.page_size as usize - page_offset).min(buf.len() - position);
+            let piece = &mut buf[position..(position + length)];
+
+            if let Some(page) = state.dirty_pages.get(&page_number) {
+                piece.copy_from_slice(&page[page_offset..(page_offset + length)]);
+            } else if page_number * self.page_size < state.original_length {
+                self.storage
+                    .read()
+                    .unwrap()
+                    .read_at(offset + position as u64, piece)?;
+            } else {
+                piece.fill(0);
+            }
+
+            position += length;
+        }
+
+        Ok(())
+    }
+
+    /// Writes to the image, or only to the dirty pages of the running transaction if there is one.
+    fn write_bytes(&self, offset: u64, buf: &[u8]) -> Result<(), Error> {
+        if !self.is_in_transaction() {
+            self.storage.write().unwrap().write_at(offset, buf)?;
+            return Ok(());
+        }
+
+        let mut position = 0;
+        while position < buf.len() {
+            let page_number = (offset + position as u64) / self.page_size;
+            let page_offset = ((offset + position as u64) % self.page_size) as usize;
+            let length = (self.page_size as usize - page_offset).min(buf.len() - position);
+
+            let mut page = vec![0u8; self.page_size as usize];
+            self.read_bytes(page_number * self.page_size, &mut page)?;
+            page[page_offset..(page_offset + length)]
+                .copy_from_slice(&buf[position..(position + length)]);
+
+            let mut transaction = self.transaction.lock().unwrap();
+            let state = transaction.as_mut().unwrap();
+            state.dirty_pages.insert(page_number, page);
+
+            position += length;
+        }
+
+        Ok(())
+    }
+
+    fn get_physical_len(&self) -> Result<u64, Error> {
+        match self.transaction.lock().unwrap().as_ref() {
+            Some(state) => Ok(state.length),
+            None => Ok(self.storage.read().unwrap().len()?),
+        }
+    }
+
+    fn set_physical_len(&self, length: u64) -> Result<(), Error> {
+        match self.transaction.lock().unwrap().as_mut() {
+            Some(state) => {
+                //pages cut off by the transaction come back zeroed, like they would on disk
+                let first_removed_page = ceil_div(length, self.page_size);
+                state
+                    .dirty_pages
+                    .retain(|&page, _| page < first_removed_page);
+                if length < state.original_length {
+                    for page in first_removed_page..(state.original_length / self.page_size) {
+                        state
+                            .dirty_pages
+                            .insert(page, vec![0u8; self.page_size as usize]);
+                    }
+                }
+                state.length = length;
+            }
+            None => self.storage.write().unwrap().set_len(length)?,
+        }
+        Ok(())
+    }
+
+    fn begin_transaction(&self) -> Result<(), Error> {
+        let original_length = self.get_physical_len()?;
+        *self.transaction.lock().unwrap() = Some(TransactionState {
+            dirty_pages: BTreeMap::new(),
+            pending: Vec::new(),
+            length: original_length,
+            original_length,
+            original_header: [
+                self.file_table_page,
+                self.file_table_size,
+                self.allocation_table_page,
+                self.allocation_table_size,
+            ],
+        });
+        self.in_transaction.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    fn is_in_transaction(&self) -> bool {
+        self.in_transaction.load(Ordering::Acquire)
+    }
+
+    fn take_transaction(&self) -> TransactionState {
+        self.in_transaction.store(false, Ordering::Release);
+        self.transaction.lock().unwrap().take().unwrap()
+    }
+
+    /// Puts every page the transaction changed in place with a single journaled commit.
+    fn commit_transaction(&mut self) -> Result<(), Error> {
+        if self.stage_transaction()? {
+            self.resolve_commit()?;
+        }
+        Ok(())
+    }
+
+    /// Ends the transaction by writing the changed pages after the end of the image and scheduling
+    /// the commit that copies them in place, so replaying it after a crash copies them again.
+    fn stage_transaction(&mut self) -> Result<bool, Error> {
+        let state = self.take_transaction();
+        let staged = self.write_staged_pages(&state);
+        if staged.is_err() {
+            //nothing of it was put in place, so it ends like a rollback
+            [
+                self.file_table_page,
+                self.file_table_size,
+                self.allocation_table_page,
+                self.allocation_table_size,
+            ] = state.original_header;
+        }
+        staged
+    }
+
+    fn write_staged_pages(&self, state: &TransactionState) -> Result<bool, Error> {
+        if state.dirty_pages.is_empty() {
+            return Ok(false);
+        }
+
+        let staging_page = state.length.max(state.original_length) / self.page_size;
+        let number_of_pages = state.dirty_pages.len() as u64;
+        let index_size = ceil_div(number_of_pages * size_of::<u64>() as u64, self.page_size);
+
+        let mut index = Vec::new();
+        for page_number in state.dirty_pages.keys() {
+            index.extend_from_slice(&page_number.to_le_bytes());
+        }
+        self.set_physical_len((staging_page + index_size + number_of_pages) * self.page_size)?;
+        self.write_bytes(staging_page * self.page_size, &index)?;
+        for (i, page) in state.dirty_pages.values().enumerate() {
+            self.write_bytes(
+                (staging_page + index_size + i as u64) * self.page_size,
+                page,
+            )?;
+        }
+
+        self.sync_before_commit()?;
+        self.schedule_commit(vec![Modification::Pages(staging_page, number_of_pages)])?;
+
+        Ok(true)
+    }
+
+    fn rollback_transaction(&mut self) {
+        let state = self.take_transaction();
+        [
+            self.file_table_page,
+            self.file_table_size,
+            self.allocation_table_page,
+            self.allocation_table_size,
+        ] = state.original_header;
+    }
+
+    fn copy_staged_pages(&mut self, staging_page: u64, number_of_pages: u64) -> Result<(), Error> {
+        let index_size = ceil_div(number_of_pages * size_of::<u64>() as u64, self.page_size);
+        let mut index = vec![0u8; (number_of_pages as usize) * size_of::<u64>()];
+        self.read_bytes(staging_page * self.page_size, &mut index)?;
+
+        let mut page = vec![0u8; self.page_size as usize];
+        for (i, page_number) in index.chunks(size_of::<u64>()).enumerate() {
+            let page_number = u64::from_le_bytes(page_number.try_into().unwrap());
+            self.read_bytes(
+                (staging_page + index_size + i as u64) * self.page_size,
+                &mut page,
+            )?;
+            self.write_bytes(page_number * self.page_size, &page)?;
+        }
+
+        //the header may have been part of it
+        let mut header = [0u8; HEADER_SIZE];
+        self.read_bytes(0, &mut header)?;
+        let read_u64 =
+            |offset: usize| u64::from_le_bytes(header[offset..(offset + 8)].try_into().unwrap());
+        self.file_table_page = read_u64(16);
+        self.file_table_size = read_u64(24);
+        self.allocation_table_page = read_u64(32);
+        self.allocation_table_size = read_u64(40);
+        Ok(())
+    }
+
+    /// Writes the journal of a commit. The records go right after the journal header when they fit
+    /// in the commit page and into pages past the end of the image otherwise. The header goes last.
+    fn schedule_commit(&self, modifications: Vec<Modification>) -> Result<(), Error> {
+        //inside a transaction everything waits for the commit of the whole transaction
+        if let Some(state) = self.transaction.lock().unwrap().as_mut() {
+            state.pending.extend(modifications);
+            return Ok(());
+        }
+
+        //records carry the sequence number in their checksum, so leftovers of an older journal never pass
+        let sequence = self.journal_sequence.load(Ordering::Relaxed) + 1;
+        self.journal_sequence.store(sequence, Ordering::Relaxed);
+
+        let mut bytes: Vec<u8> = Vec::new();
+        let count = modifications.len() as u64;
+        for modification in modifications {
+            let record: Vec<u8> = modification.into();
+            bytes.extend_from_slice(&(record.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&record);
+            bytes.extend_from_slice(&record_checksum(sequence, &record).to_le_bytes());
+        }
+
+        let journal_index = DEFAULT_SYSTEM_COMMIT_PAGE * self.page_size;
+        let overflow_page = if JOURNAL_HEADER_SIZE + bytes.len() as u64 <= self.page_size {
+            0
+        } else {
+            let overflow_page = ceil_div(self.get_physical_len()?, self.page_size);
+            let overflow_size = ceil_div(bytes.len() as u64, self.page_size);
+            self.set_physical_len((overflow_page + overflow_size) * self.page_size)?;
+            overflow_page
+        };
+
+        let records_index = match overflow_page {
+            0 => journal_index + JOURNAL_HEADER_SIZE,
+            overflow_page => overflow_page * self.page_size,
+        };
+        self.write_bytes(records_index, &bytes)?;
+        self.storage.write().unwrap().flush()?;
+        self.sync(Durability::Full)?;
+
+        self.write_journal_header(count, bytes.len() as u64, overflow_page)?;
+        self.storage.write().unwrap().flush()?;
+        self.sync(Durability::MetadataOnly)
+    }
+
+    /// Calls `sync_data` on the host file when the durability setting is at least `level`.
+    fn sync(&self, level: Durability) -> Result<(), Error> {
+        if self.durability >= level {
+            self.storage.write().unwrap().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Gets pages written outside of the journal to disk before a commit points at them, as the
+    /// commit record can't tell whether they made it there.
+    fn sync_before_commit(&self) -> Result<(), Error> {
+        self.sync(Durability::MetadataOnly)
+    }
+
+    fn write_journal_header(
+        &self,
+        count: u64,
+        length: u64,
+        overflow_page: u64,
+    ) -> Result<(), Error> {
+        let mut header = Vec::new();
+        header.extend_from_slice(&count.to_le_bytes());
+        header.extend_from_slice(&length.to_le_bytes());
+        header.extend_from_slice(&overflow_page.to_le_bytes());
+        header.extend_from_slice(&self.journal_sequence.load(Ordering::Relaxed).to_le_bytes());
+        header.extend_from_slice(&(crc32(&header) as u64).to_le_bytes());
+        self.write_bytes(DEFAULT_SYSTEM_COMMIT_PAGE * self.page_size, &header)
+    }
+
+    /// Reads back the records of the journal, together with its sequence number. The records are
+    /// `None` when they don't add up, which means the journal was never completely written.
+    fn read_journal(&self) -> Result<(u64, Option<Vec<Modification>>), Error> {
+        let journal_index = DEFAULT_SYSTEM_COMMIT_PAGE * self.page_size;
+        let mut header = [0u8; JOURNAL_HEADER_SIZE as usize];
+        self.read_bytes(journal_index, &mut header)?;
+
+        let read_u64 =
+            |offset: usize| u64::from_le_bytes(header[offset..(offset + 8)].try_into().unwrap());
+        let count = read_u64(0);
+        let length = read_u64(8);
+        let overflow_page = read_u64(16);
+        let sequence = read_u64(24);
+
+        //a new image starts out with a zeroed journal
+        if header.iter().all(|&byte| byte == 0) {
+            return Ok((0, Some(Vec::new())));
+        }
+        if read_u64(32) != crc32(&header[..32]) as u64 {
+            return Ok((0, None));
+        }
+        if count == 0 {
+            return Ok((sequence, Some(Vec::new())));
+        }
 
-pub struct Vfs {
-    internal: Rc<RefCell<VfsInternal>>,
-}
+        let Some(records_index) = self.get_journal_records_index(length, overflow_page) else {
+            return Ok((sequence, None));
+        };
+        let mut bytes = vec![0u8; length as usize];
+        self.read_bytes(records_index, &mut bytes)?;
 
-impl Vfs {
-    pub fn open(filename: &str) -> Vfs {
-        Vfs {
-            internal: Rc::new(RefCell::new(VfsInternal::open(filename))),
+        Ok((sequence, parse_journal_records(&bytes, count, sequence)))
+    }
+
+    /// Where the records of a journal start, `None` when they wouldn't be inside the image.
+    fn get_journal_records_index(&self, length: u64, overflow_page: u64) -> Option<u64> {
+        let journal_index = DEFAULT_SYSTEM_COMMIT_PAGE * self.page_size;
+
+        let records_index = match overflow_page {
+            0 if JOURNAL_HEADER_SIZE.checked_add(length)? <= self.page_size => {
+                journal_index + JOURNAL_HEADER_SIZE
+            }
+            0 => return None,
+            overflow_page => overflow_page.checked_mul(self.page_size)?,
+        };
+        if records_index.checked_add(length)? > self.get_physical_len().ok()? {
+            return None;
         }
+
+        Some(records_index)
     }
 
-    pub fn create_dir(&self, path: &str) -> Result<(), Error> {
-        self.create_file_entity(path, true)?;
-        Ok(())
+    fn resolve_commit(&mut self) -> Result<JournalRecovery, Error> {
+        let pending = self
+            .transaction
+            .lock()
+            .unwrap()
+            .as_mut()
+            .map(|state| std::mem::take(&mut state.pending));
+        if let Some(pending) = pending {
+            for modification in pending {
+                self.apply_modification(modification)?;
+            }
+            let number_of_pages = self.get_number_of_pages()?;
+            self.set_physical_len(number_of_pages * self.page_size)?;
+            return Ok(JournalRecovery::Clean);
+        }
+
+        let (sequence, journal) = self.read_journal()?;
+        self.journal_sequence.fetch_max(sequence, Ordering::Relaxed);
+
+        //a journal that was not written completely is dropped, the commit never happened
+        let recovery = match journal {
+            None => JournalRecovery::Discarded,
+            Some(modifications) if modifications.is_empty() => JournalRecovery::Clean,
+            Some(modifications) => {
+                for modification in modifications {
+                    self.apply_modification(modification)?;
+                }
+                //everything has to be in place before the journal goes away
+                self.storage.write().unwrap().flush()?;
+                self.sync(Durability::MetadataOnly)?;
+                JournalRecovery::Replayed
+            }
+        };
+
+        //the journal is cleared before the image is cut down, staged pages may still be needed for replay
+        self.write_journal_header(0, 0, 0)?;
+        self.storage.write().unwrap().flush()?;
+        {
+            let length = self.get_number_of_pages()? * self.page_size;
+            //otherwise the disk may cut the image down first
+            if length < self.get_physical_len()? {
+                self.sync(Durability::MetadataOnly)?;
+            }
+            self.set_physical_len(length)?;
+        }
+
+        Ok(recovery)
     }
 
-    pub fn create(&self, path: &str) -> Result<File, Error> {
-        self.create_file_entity(path, false)
+    fn apply_modification(&mut self, modification: Modification) -> Result<(), Error> {
+        {
+            match modification {
+                Modification::FileTable(index, file) => self.update_file_by_index(index, file),
+                Modification::AllcationTable(range, is_allocated) => {
+                    self.allocate_page_range(range, is_allocated)
+                }
+                Modification::SystemHeader(file_table_page, file_table_size) => {
+                    self.file_table_page = file_table_page;
+                    self.file_table_size = file_table_size;
+                    self.update_header(file_table_page, file_table_size)
+                }
+                Modification::AllocationTableHeader(
+                    allocation_table_page,
+                    allocation_table_size,
+                ) => {
+                    self.allocation_table_page = allocation_table_page;
+                    self.allocation_table_size = allocation_table_size;
+                    self.update_allocation_table_header(
+                        allocation_table_page,
+                        allocation_table_size,
+                    )
+                }
+                Modification::ExtentMap(page_number, extent_page) => {
+                    self.update_extent_page(page_number, extent_page)
+                }
+                Modification::Pages(staging_page, number_of_pages) => {
+                    self.copy_staged_pages(staging_page, number_of_pages)
+                }
+            }
+        }
     }
 
-    fn create_file_entity(&self, path: &str, is_directory: bool) -> Result<File, Error> {
+    fn update_allocation_table_header(
+        &self,
+        allocation_table_page: u64,
+        allocation_table_size: u64,
+    ) -> Result<(), Error> {
+        self.write_bytes(32, &allocation_table_page.to_le_bytes())?;
+        self.write_bytes(40, &allocation_table_size.to_le_bytes())
+    }
+
+    fn update_header(&self, file_table_page: u64, file_table_size: u64) -> Result<(), Error> {
+        self.write_bytes(16, &file_table_page.to_le_bytes())?;
+        self.write_bytes(24, &file_table_size.to_le_bytes())
+    }
+
+    /// Creates an entry at `path` in a single commit, growing the file table first if it is full.
+    /// Returns the index of the new entry.
+    fn create_entity(&mut self, path: &str, is_directory: bool) -> Result<u64, Error> {
         let mut path_componenets = path.split('/');
 
         let name = path_componenets.next_back().unwrap();
@@ -565,10 +4852,10 @@ impl Vfs {
             return Err(Error::FileNameTooBig);
         }
 
-        let mut internal = self.internal.borrow_mut();
+        self.check_writable()?;
 
         let mut previous_pointer = 0;
-        let mut child_entity = internal.get_file_struct_by_index(previous_pointer);
+        let mut child_entity = self.get_file_struct_by_index(previous_pointer)?;
         let mut current_pointer = child_entity.contents;
 
         for path_dir in path_componenets {
@@ -579,7 +4866,7 @@ impl Vfs {
             let mut found = false;
 
             while current_pointer != 0 {
-                child_entity = internal.get_file_struct_by_index(current_pointer);
+                child_entity = self.get_file_struct_by_index(current_pointer)?;
 
                 if child_entity.name == path_dir && child_entity.is_active {
                     if child_entity.is_directory {
@@ -608,7 +4895,7 @@ impl Vfs {
         let is_parent_dir_empty = current_pointer == 0;
 
         while current_pointer != 0 {
-            child_entity = internal.get_file_struct_by_index(current_pointer);
+            child_entity = self.get_file_struct_by_index(current_pointer)?;
 
             if child_entity.name == name && child_entity.is_active {
                 return Err(Error::NameAlreadyInUse);
@@ -618,24 +4905,20 @@ impl Vfs {
             current_pointer = child_entity.next;
         }
 
-        let new_index = internal.find_inactive_file_slot();
+        let new_index = self.find_inactive_file_slot()?;
 
         if new_index.is_none() {
-            let number_of_pages_needed = internal.file_table_size + 1;
-            let temp = internal.find_first_fitting_page_range(
-                number_of_pages_needed,
-                internal.file_table_page..(internal.file_table_page + internal.file_table_size),
-            );
-            if temp.is_none() {
-                return Err(std::io::Error::from(std::io::ErrorKind::OutOfMemory).into());
-            }
-            let contents_location = temp.unwrap();
+            let number_of_pages_needed = self.file_table_size + 1;
+            let file_table_range =
+                self.file_table_page..(self.file_table_page + self.file_table_size);
+            let contents_location =
+                self.find_free_page_range(number_of_pages_needed, file_table_range)?;
 
             //critical
             let mut modifications = Vec::new();
 
             modifications.push(Modification::AllcationTable(
-                internal.file_table_page..(internal.file_table_page + internal.file_table_size),
+                self.file_table_page..(self.file_table_page + self.file_table_size),
                 false,
             ));
             modifications.push(Modification::AllcationTable(
@@ -643,11 +4926,11 @@ impl Vfs {
                 true,
             ));
 
-            let mut last_page = internal.get_number_of_pages();
-            if internal.file_table_page <= last_page
-                && last_page < internal.file_table_page + internal.file_table_size
+            let mut last_page = self.get_number_of_pages()?;
+            if self.file_table_page <= last_page
+                && last_page < self.file_table_page + self.file_table_size
             {
-                last_page = internal.file_table_page - 1;
+                last_page = self.file_table_page - 1;
             }
             if last_page < contents_location + number_of_pages_needed - 1 {
                 last_page = contents_location + number_of_pages_needed - 1;
@@ -655,41 +4938,33 @@ impl Vfs {
             let vfs_page_total = last_page + 1;
 
             {
-                let mut physical_file = internal.physical_file.borrow_mut();
-                physical_file.set_len(vfs_page_total * internal.page_size)?;
-
-                let mut contents_buffer: Vec<u8> = Vec::new();
-                contents_buffer.resize((internal.file_table_size * internal.page_size) as usize, 0);
-                physical_file
-                    .seek(SeekFrom::Start(
-                        internal.file_table_page * internal.page_size,
-                    ))
-                    .unwrap();
-                physical_file.read_exact(&mut contents_buffer).unwrap();
+                self.set_physical_len(vfs_page_total * self.page_size)?;
 
-                physical_file
-                    .seek(SeekFrom::Start(contents_location * internal.page_size))
-                    .unwrap();
-                physical_file.write_all(&contents_buffer).unwrap();
+                let mut contents_buffer =
+                    vec![0u8; (self.file_table_size * self.page_size) as usize];
+                self.read_bytes(self.file_table_page * self.page_size, &mut contents_buffer)?;
+                self.write_bytes(contents_location * self.page_size, &contents_buffer)?;
             }
 
             //critical
 
-            internal.file_table_page = contents_location;
-            internal.file_table_size += 1;
+            self.sync_before_commit()?;
+
+            self.file_table_page = contents_location;
+            self.file_table_size += 1;
 
             modifications.push(Modification::SystemHeader(
-                internal.file_table_page,
-                internal.file_table_size,
+                self.file_table_page,
+                self.file_table_size,
             ));
 
-            internal.schedule_commit(modifications);
-            internal.resolve_commit();
+            self.schedule_commit(modifications)?;
+            self.resolve_commit()?;
         }
 
-        let new_index = internal.find_inactive_file_slot().unwrap();
+        let new_index = self.find_inactive_file_slot()?.unwrap();
 
-        let time = VfsInternal::get_system_time();
+        let time = Self::get_system_time();
 
         let new_entity = FileStruct {
             is_root: false,
@@ -714,735 +4989,979 @@ impl Vfs {
             Modification::FileTable(previous_pointer, child_entity),
         ];
 
-        internal.schedule_commit(modifications);
-        internal.resolve_commit();
-
-        Ok(File {
-            internal: Rc::downgrade(&self.internal),
-            file_index: new_index,
-            cursor: 0,
-            path: path.to_owned(),
-        })
-    }
+        self.schedule_commit(modifications)?;
+        self.resolve_commit()?;
 
-    pub fn print_tree(&self) {
-        let internal = self.internal.borrow_mut();
-        let root_contents = internal.get_file_struct_by_index(0).contents;
-        internal.print_tree_recursive(root_contents, 0);
+        Ok(new_index)
     }
 
-    pub fn read_dir(&self, path: &str) -> Result<DirIterator, Error> {
-        let internal = self.internal.borrow_mut();
-        let temp = internal.get_file_struct_by_path(path);
-
-        if temp.is_err() {
-            return Err(Error::DirectoryNotFound);
-        }
-
-        let dir = temp.unwrap().1;
-
-        let mut entry_names = Vec::new();
+    /// Deletes an entry and everything below it in a single commit.
+    fn delete_entity(&mut self, parent_index: u64, index: u64) -> Result<(), Error> {
+        let mut staged = BTreeMap::new();
+        let mut modifications = Vec::new();
 
-        let mut pointer = dir.contents;
-        while pointer != 0 {
-            let entry = internal.get_file_struct_by_index(pointer);
-            entry_names.push(entry.name);
-            pointer = entry.next;
-        }
+        self.unlink_entry(&mut staged, parent_index, index)?;
+        self.deactivate_recursive(&mut staged, &mut modifications, index)?;
 
-        if dir.is_directory {
-            Ok(DirIterator {
-                cursor: 0,
-                internal: Rc::downgrade(&self.internal),
-                path: path.to_owned(),
-                entry_names,
-            })
-        } else {
-            Err(Error::DirectoryNotFound)
+        for (index, file) in staged {
+            modifications.push(Modification::FileTable(index, file));
         }
-    }
 
-    pub fn open_entry(&self, file: DirEntry) -> Result<File, Error> {
-        self.open_file(&file.path)
+        self.schedule_commit(modifications)?;
+        self.resolve_commit()?;
+        Ok(())
     }
 
-    pub fn open_file(&self, path: &str) -> Result<File, Error> {
-        let internal = self.internal.borrow_mut();
-        let temp = internal.get_file_struct_by_path(path);
-
-        if temp.is_err() {
-            return Err(Error::FileNotFound);
-        }
-
-        let (index, file) = temp.unwrap();
+    fn deactivate_recursive(
+        &self,
+        staged: &mut BTreeMap<u64, FileStruct>,
+        modifications: &mut Vec<Modification>,
+        index: u64,
+    ) -> Result<(), Error> {
+        let mut entity = self.get_staged_file_struct(staged, index)?;
 
-        if !file.is_directory {
-            Ok(File {
-                file_index: index,
-                cursor: 0,
-                internal: Rc::downgrade(&self.internal),
-                path: path.to_owned(),
-            })
+        if entity.is_directory {
+            let mut current_pointer = entity.contents;
+            while current_pointer != 0 {
+                let next = self.get_staged_file_struct(staged, current_pointer)?.next;
+                self.deactivate_recursive(staged, modifications, current_pointer)?;
+                current_pointer = next;
+            }
         } else {
-            Err(Error::FileNotFound)
-        }
-    }
-
-    pub fn delete(&self, path: &str) -> Result<(), Error> {
-        if path.is_empty() {
-            return Ok(());
+            modifications.append(&mut self.release_file_pages(&entity)?);
         }
-        let mut path_componenets = path.split('/');
-
-        let name = path_componenets.next_back().unwrap();
 
-        let internal = self.internal.borrow_mut();
-
-        let mut previous_pointer = 0;
-        let mut child_entity = internal.get_file_struct_by_index(previous_pointer);
-        let mut current_pointer = child_entity.contents;
-
-        for path_dir in path_componenets {
-            if path_dir.is_empty() {
-                continue;
-            }
-
-            let mut found = false;
-
-            while current_pointer != 0 {
-                child_entity = internal.get_file_struct_by_index(current_pointer);
+        entity.is_active = false;
+        staged.insert(index, entity);
+        Ok(())
+    }
 
-                if child_entity.name == path_dir && child_entity.is_active {
-                    if child_entity.is_directory {
-                        previous_pointer = current_pointer;
-                        current_pointer = child_entity.contents;
-                        found = true;
-                        break;
-                    } else {
-                        return Err(Error::DirectoryNotFound);
-                    }
+    fn release_file_pages(&self, file: &FileStruct) -> Result<Vec<Modification>, Error> {
+        let mut modifications = Vec::new();
+        for (page_number, extent_page) in self.get_extent_map(file)? {
+            modifications.push(Modification::AllcationTable(
+                page_number..(page_number + 1),
+                false,
+            ));
+            for extent in extent_page.extents {
+                if !is_hole(&extent) {
+                    modifications.push(Modification::AllcationTable(extent, false));
                 }
-
-                current_pointer = child_entity.next;
-            }
-
-            if !found {
-                return Err(Error::DirectoryNotFound);
             }
         }
+        Ok(modifications)
+    }
 
-        //previous_pointer contains parent directory of where we want to create the entity
-        //current_pointer contains first entity in parent directory
-
-        let previous_pointer_copy = previous_pointer;
-        let mut found = false;
-
-        while current_pointer != 0 {
-            child_entity = internal.get_file_struct_by_index(current_pointer);
-
-            if child_entity.name == name && child_entity.is_active {
-                found = true;
-                break;
-            }
+    /// Shrinks or grows a file to `size` bytes in a single commit, new bytes read back as zeros.
+    fn set_file_len(&mut self, index: u64, size: u64) -> Result<(), Error> {
+        let mut file = self.get_file_struct_by_index(index)?;
+        let extents = self.get_extents(&file)?;
+        let number_of_pages_needed = ceil_div(size, self.page_size);
+        let number_of_current_pages: u64 =
+            extents.iter().map(|extent| extent.end - extent.start).sum();
 
-            previous_pointer = current_pointer;
-            current_pointer = child_entity.next;
+        let mut modifications = Vec::new();
+        if number_of_pages_needed < number_of_current_pages {
+            modifications = self.shrink_file(&mut file, number_of_pages_needed)?;
         }
 
-        if !found {
-            return Err(Error::FileNotFound);
-        }
+        //critical
+        //growing leaves a hole, only what is left of pages the file already has needs zeroing
+        self.zero_file_data(&extents, file.size, size)?;
 
-        let is_previous_pointer_parent_dir = previous_pointer == previous_pointer_copy;
+        file.size = size;
+        file.last_write_time = Self::get_system_time();
+        modifications.push(Modification::FileTable(index, file));
 
-        internal.delete_recursive(
-            previous_pointer,
-            current_pointer,
-            is_previous_pointer_parent_dir,
-        );
+        self.schedule_commit(modifications)?;
+        self.resolve_commit()?;
 
         Ok(())
     }
-}
-struct VfsInternal {
-    physical_file: RefCell<fs::File>,
-    page_size: u64,
-    file_table_page: u64,
-    file_table_size: u64,
-}
-
-impl VfsInternal {
-    fn open(filename: &str) -> VfsInternal {
-        let new_vfs;
-
-        let already_exists = Path::new(filename).exists();
-
-        if !already_exists {
-            let file = fs::File::options()
-                .read(true)
-                .write(true)
-                .create(true)
-                .open(filename)
-                .unwrap();
-            new_vfs = VfsInternal::default(file);
-
-            let file_table_index = new_vfs.get_file_table_index();
-            let allocation_table_index = DEFAULT_ALLOCATION_TABLE_PAGE * new_vfs.page_size;
-            let mut file = new_vfs.physical_file.borrow_mut();
-
-            //make room for header + allocation table + system commit + file table
-            file.set_len(4 * new_vfs.page_size).unwrap();
-
-            //leave space for nullptr, then write page size
-            file.seek(SeekFrom::Start(size_of::<u64>() as u64)).unwrap();
-            file.write_all(&new_vfs.page_size.to_le_bytes()).unwrap();
-
-            //write page number of file table
-            file.write_all(&new_vfs.file_table_page.to_le_bytes())
-                .unwrap();
-            file.write_all(&new_vfs.file_table_size.to_le_bytes())
-                .unwrap();
-
-            //allocate system pages
-            file.seek(SeekFrom::Start(allocation_table_index)).unwrap();
-            file.write_all(&[0b0000_1111]).unwrap();
-
-            //write root in file table page
-            file.seek(SeekFrom::Start(file_table_index)).unwrap();
-            file.write_all(&Self::make_root()).unwrap();
-        } else {
-            let mut file = fs::File::options()
-                .read(true)
-                .write(true)
-                .open(filename)
-                .unwrap();
-            let mut int_buffer = [0u8; size_of::<u64>()];
-
-            //read page size
-            file.seek(SeekFrom::Start(size_of::<u64>() as u64)).unwrap();
-            file.read_exact(&mut int_buffer).unwrap();
-            let page_size = u64::from_le_bytes(int_buffer);
-
-            //read file table page
-            file.read_exact(&mut int_buffer).unwrap();
-            let file_table_page = u64::from_le_bytes(int_buffer);
-
-            //read file table size
-            file.read_exact(&mut int_buffer).unwrap();
-            let file_table_size = u64::from_le_bytes(int_buffer);
-
-            new_vfs = VfsInternal {
-                physical_file: RefCell::new(file),
-                page_size,
-                file_table_page,
-                file_table_size,
-            };
-
-            new_vfs.resolve_commit();
-        }
 
-        new_vfs
+    /// Indices of all slots of the file table, the root directory is in the first one.
+    fn get_file_slots(&self) -> impl Iterator<Item = u64> {
+        let table_size = self.file_table_size * self.page_size;
+        (0..)
+            .map(|slot| slot * FILE_STRUCT_SIZE as u64)
+            .take_while(move |index| index + (FILE_STRUCT_SIZE as u64) < table_size)
     }
 
-    fn default(physical_file: fs::File) -> VfsInternal {
-        VfsInternal {
-            physical_file: RefCell::new(physical_file),
-            page_size: DEFAULT_PAGE_SIZE,
-            file_table_page: DEFAULT_FILE_TABLE_PAGE,
-            file_table_size: 1,
+    /// Every physical page of an active file, its extent map included, in order.
+    fn get_file_pages(&self, file: &FileStruct) -> Result<Vec<u64>, Error> {
+        if file.is_directory {
+            return Ok(Vec::new());
+        }
+        let mut pages = Vec::new();
+        for (page_number, extent_page) in self.get_extent_map(file)? {
+            pages.push(page_number);
+            for extent in extent_page.extents {
+                if !is_hole(&extent) {
+                    pages.extend(extent);
+                }
+            }
         }
+        pages.sort_unstable();
+        Ok(pages)
     }
 
-    fn make_root() -> FileBytes {
-        let time = Self::get_system_time();
-        let root = FileStruct {
-            is_root: true,
-            is_active: true,
-            is_directory: true,
-            name: "root".to_owned(),
-            contents: 0,
-            next: 0,
-            size: 0,
-            creation_time: time,
-            last_write_time: time,
-        };
-        root.try_into().unwrap()
-    }
+    /// Free runs of pages before `limit`, pages past the end of the allocation table count as free.
+    fn get_free_page_runs(&self, limit: u64) -> Result<Vec<std::ops::Range<u64>>, Error> {
+        let table = self.read_allocation_table()?;
+        let capacity = self.get_allocation_table_capacity().min(limit);
+
+        let mut free_runs = Vec::new();
+        let mut run_start = None;
+        for page in 0..capacity {
+            if table[(page / 8) as usize] & 1 << (page % 8) != 0 {
+                if let Some(start) = run_start.take() {
+                    free_runs.push(start..page);
+                }
+            } else if run_start.is_none() {
+                run_start = Some(page);
+            }
+        }
 
-    fn get_file_struct_by_index(&self, index: u64) -> FileStruct {
-        if index + FILE_STRUCT_SIZE as u64 >= self.file_table_size * self.page_size {
-            return [0; FILE_STRUCT_SIZE].into();
+        let tail_start = run_start.unwrap_or(capacity);
+        if tail_start < limit {
+            free_runs.push(tail_start..limit);
         }
-        let mut physical_file = self.physical_file.borrow_mut();
-        physical_file
-            .seek(SeekFrom::Start(
-                self.file_table_page * self.page_size + index,
-            ))
-            .unwrap();
-        let mut file_struct_buffer = [0u8; FILE_STRUCT_SIZE];
-        physical_file.read_exact(&mut file_struct_buffer).unwrap();
-        FileStruct::from(file_struct_buffer)
+        Ok(free_runs)
     }
 
-    fn get_file_struct_by_path(&self, path: &str) -> Result<(u64, FileStruct), Error> {
-        let mut path_componenets = path.split('/');
-
-        let name = path_componenets.next_back().unwrap();
-
-        let mut child_entity = self.get_file_struct_by_index(0);
-        let mut current_pointer = child_entity.contents;
+    fn get_stats(&self) -> Result<VfsStats, Error> {
+        let total_pages = match self.max_pages {
+            u64::MAX => self.get_number_of_pages()?,
+            max_pages => max_pages,
+        };
+        let free_runs = self.get_free_page_runs(total_pages)?;
+        let free_pages: u64 = free_runs.iter().map(|run| run.end - run.start).sum();
 
-        for path_dir in path_componenets {
-            if path_dir.is_empty() {
-                continue;
+        let mut flags = [0u8; 1];
+        let (mut file_slots_used, mut file_slots_free) = (0, 0);
+        for index in self.get_file_slots() {
+            self.read_bytes(self.get_file_table_index() + index, &mut flags)?;
+            //is active
+            if flags[0] & 0b10 != 0 {
+                file_slots_used += 1;
+            } else {
+                file_slots_free += 1;
             }
+        }
 
-            let mut found = false;
+        Ok(VfsStats {
+            page_size: self.page_size,
+            total_pages,
+            used_pages: total_pages - free_pages,
+            free_pages,
+            largest_free_run: free_runs
+                .iter()
+                .map(|run| run.end - run.start)
+                .max()
+                .unwrap_or(0),
+            file_slots_used,
+            file_slots_free,
+        })
+    }
 
-            while current_pointer != 0 {
-                child_entity = self.get_file_struct_by_index(current_pointer);
+    fn get_layout_report(&self) -> Result<LayoutReport, Error> {
+        let mut owned = vec![
+            (0..1, PageOwner::SystemHeader),
+            (
+                self.allocation_table_page
+                    ..(self.allocation_table_page + self.allocation_table_size),
+                PageOwner::AllocationTable,
+            ),
+            (
+                DEFAULT_SYSTEM_COMMIT_PAGE..(DEFAULT_SYSTEM_COMMIT_PAGE + 1),
+                PageOwner::Journal,
+            ),
+            (
+                self.file_table_page..(self.file_table_page + self.file_table_size),
+                PageOwner::FileTable,
+            ),
+        ];
 
-                if child_entity.name == path_dir && child_entity.is_active {
-                    if child_entity.is_directory {
-                        current_pointer = child_entity.contents;
-                        found = true;
-                        break;
-                    } else {
-                        return Err(Error::DirectoryNotFound);
+        let mut directories = vec![(String::new(), self.get_file_struct_by_index(0)?)];
+        while let Some((path, directory)) = directories.pop() {
+            let mut pointer = directory.contents;
+            while pointer != 0 {
+                let entity = self.get_file_struct_by_index(pointer)?;
+                let entity_path = match path.as_str() {
+                    "" => entity.name.clone(),
+                    path => format!("{}/{}", path, entity.name),
+                };
+                pointer = entity.next;
+
+                if entity.is_directory {
+                    directories.push((entity_path, entity));
+                    continue;
+                }
+                for (page_number, extent_page) in self.get_extent_map(&entity)? {
+                    owned.push((
+                        page_number..(page_number + 1),
+                        PageOwner::ExtentMap(entity_path.clone()),
+                    ));
+                    for extent in extent_page.extents {
+                        if !is_hole(&extent) {
+                            owned.push((extent, PageOwner::File(entity_path.clone())));
+                        }
                     }
                 }
-
-                current_pointer = child_entity.next;
-            }
-
-            if !found {
-                return Err(Error::DirectoryNotFound);
             }
         }
 
-        while current_pointer != 0 {
-            child_entity = self.get_file_struct_by_index(current_pointer);
-
-            if child_entity.name == name && child_entity.is_active {
-                return Ok((current_pointer, child_entity));
+        let number_of_pages = self.get_number_of_pages()?;
+        let free_runs = self.get_free_page_runs(number_of_pages)?;
+
+        //whatever is neither owned nor free is allocated for nothing
+        let mut pieces: Vec<(std::ops::Range<u64>, Option<PageOwner>)> = owned
+            .into_iter()
+            .map(|(pages, owner)| (pages, Some(owner)))
+            .chain(free_runs.iter().map(|run| (run.clone(), None)))
+            .collect();
+        pieces.sort_by_key(|(pages, _)| pages.start);
+
+        let mut regions: Vec<PageRegion> = Vec::new();
+        let mut position = 0;
+        let mut add_region =
+            |pages: std::ops::Range<u64>, owner: PageOwner| match regions.last_mut() {
+                Some(last) if last.pages.end == pages.start && last.owner == owner => {
+                    last.pages.end = pages.end
+                }
+                _ => regions.push(PageRegion { pages, owner }),
+            };
+        for (pages, owner) in pieces {
+            if position < pages.start {
+                add_region(position..pages.start, PageOwner::Unknown);
+            }
+            position = position.max(pages.end);
+            if let Some(owner) = owner {
+                add_region(pages, owner);
             }
-
-            current_pointer = child_entity.next;
+        }
+        if position < number_of_pages {
+            add_region(position..number_of_pages, PageOwner::Unknown);
         }
 
-        Err(Error::FileNotFound)
+        let free_pages: u64 = free_runs.iter().map(|run| run.end - run.start).sum();
+        let largest_free_run = free_runs
+            .iter()
+            .map(|run| run.end - run.start)
+            .max()
+            .unwrap_or(0);
+        let fragmentation = match free_pages {
+            0 => 0.0,
+            free_pages => 1.0 - largest_free_run as f64 / free_pages as f64,
+        };
+
+        Ok(LayoutReport {
+            page_size: self.page_size,
+            regions,
+            free_runs,
+            fragmentation,
+        })
     }
 
-    fn find_inactive_file_slot(&self) -> Option<u64> {
-        let mut flags = [0u8; 1];
-        let mut index = self.get_file_table_index();
+    /// Moves the file table and then the allocation table to the first free run before them that
+    /// fits them. Returns the number of pages moved.
+    fn move_system_tables(&mut self) -> Result<u64, Error> {
+        let find_run_before = |internal: &Self, page: u64, size: u64| -> Result<_, Error> {
+            Ok(internal
+                .get_free_page_runs(page)?
+                .into_iter()
+                .find(|run| run.end - run.start >= size)
+                .map(|run| run.start))
+        };
+        let mut pages_moved = 0;
 
-        let mut physical_file = self.physical_file.borrow_mut();
+        if let Some(location) = find_run_before(self, self.file_table_page, self.file_table_size)? {
+            let old_range = self.file_table_page..(self.file_table_page + self.file_table_size);
+            let mut table = vec![0u8; (self.file_table_size * self.page_size) as usize];
+            self.read_bytes(self.file_table_page * self.page_size, &mut table)?;
 
-        physical_file.seek(SeekFrom::Start(index)).unwrap();
-        physical_file.read_exact(&mut flags).unwrap();
+            //critical
+            self.write_bytes(location * self.page_size, &table)?;
+            //critical
 
-        while flags[0] & 0b10 != 0 {
-            //is active
-            index = physical_file
-                .seek(SeekFrom::Current(FILE_STRUCT_SIZE as i64 - 1))
-                .unwrap();
-            index -= self.get_file_table_index();
+            self.sync_before_commit()?;
 
-            if index + FILE_STRUCT_SIZE as u64 >= self.file_table_size * self.page_size {
-                return None;
+            let modifications = vec![
+                Modification::AllcationTable(old_range, false),
+                Modification::AllcationTable(location..(location + self.file_table_size), true),
+                Modification::SystemHeader(location, self.file_table_size),
+            ];
+            self.schedule_commit(modifications)?;
+            self.resolve_commit()?;
+            pages_moved += self.file_table_size;
+        }
+
+        if let Some(location) =
+            find_run_before(self, self.allocation_table_page, self.allocation_table_size)?
+        {
+            let old_range = self.allocation_table_page
+                ..(self.allocation_table_page + self.allocation_table_size);
+            let mut table = self.read_allocation_table()?;
+            for page_number in location..(location + self.allocation_table_size) {
+                table[(page_number / 8) as usize] |= 1 << (page_number % 8);
             }
 
-            physical_file.read_exact(&mut flags).unwrap();
+            //critical
+            self.write_bytes(location * self.page_size, &table)?;
+            //critical
+
+            self.sync_before_commit()?;
+
+            let modifications = vec![
+                Modification::AllocationTableHeader(location, self.allocation_table_size),
+                Modification::AllcationTable(old_range, false),
+            ];
+            self.schedule_commit(modifications)?;
+            self.resolve_commit()?;
+            pages_moved += self.allocation_table_size;
         }
 
-        Some(index)
+        Ok(pages_moved)
     }
 
-    fn get_system_time() -> u64 {
-        SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            // .as_millis() as u64
-            .as_secs()
-    }
+    /// Moves a file closer to the start of the image, see [`Vfs::defragment`] and [`Vfs::compact`].
+    /// Returns the number of pages moved.
+    fn reorganize_file(&mut self, index: u64, defragment: bool) -> Result<u64, Error> {
+        let file = self.get_file_struct_by_index(index)?;
+        if !file.is_active {
+            return Ok(0);
+        }
+        let pages = self.get_file_pages(&file)?;
+        let (Some(&first), Some(&last)) = (pages.first(), pages.last()) else {
+            return Ok(0);
+        };
+        let is_contiguous = last - first + 1 == pages.len() as u64;
+
+        if defragment {
+            return self.move_file(index, |internal, data_pages, _, extra_map_pages| {
+                let number_of_pages = data_pages.len() as u64 + 1 + extra_map_pages;
+                let Some(run) = internal
+                    .get_free_page_runs(internal.max_pages)?
+                    .into_iter()
+                    .find(|run| run.end - run.start >= number_of_pages)
+                    .filter(|run| !is_contiguous || run.start < first)
+                else {
+                    return Ok(None);
+                };
+                let data_end = run.start + data_pages.len() as u64;
+                Ok(Some((
+                    (run.start..data_end).collect(),
+                    (data_end..(run.start + number_of_pages)).collect(),
+                )))
+            });
+        }
 
-    fn get_file_table_index(&self) -> u64 {
-        self.page_size * self.file_table_page
-    }
+        //the last pages of the file go to the first free pages, as long as that is further forward
+        self.move_file(index, |internal, data_pages, map_pages, extra_map_pages| {
+            let free_runs = internal.get_free_page_runs(internal.max_pages)?;
+            let mut free_pages = free_runs.into_iter().flatten().peekable();
+            let mut own_pages: Vec<u64> = data_pages.iter().chain(map_pages).copied().collect();
+            own_pages.sort_unstable_by(|a, b| b.cmp(a));
+
+            let mut moves = BTreeMap::new();
+            for page_number in own_pages {
+                match free_pages.next_if(|&free_page| free_page < page_number) {
+                    Some(free_page) => moves.insert(page_number, free_page),
+                    None => break,
+                };
+            }
+            if moves.is_empty() {
+                return Ok(None);
+            }
 
-    fn print_tree_recursive(&self, from_index: u64, depth: u64) {
-        let mut pointer = from_index;
-        let mut entity: FileStruct;
-        while pointer != 0 {
-            entity = self.get_file_struct_by_index(pointer);
+            let extra_pages: Vec<u64> = free_pages.take(extra_map_pages as usize).collect();
+            let moved = |page_number: &u64| *moves.get(page_number).unwrap_or(page_number);
+            Ok(Some((
+                data_pages.iter().map(moved).collect(),
+                map_pages.iter().map(moved).chain(extra_pages).collect(),
+            )))
+        })
+    }
 
-            if entity.is_active {
-                print!("{}", "---".repeat(depth as usize));
-                println!(
-                    "{} : {}",
-                    entity.name,
-                    if entity.is_directory {
-                        "directory"
-                    } else {
-                        "file"
-                    }
-                );
+    /// Moves the pages of a file to the ones `find_pages` picks and switches the file over to them
+    /// in a single commit. `find_pages` gets the data pages of the file in order, its extent map
+    /// pages and how many more the extent map needs, and returns where each of them goes, or `None`
+    /// to leave the file where it is. A page may only stay where it is or go to a free page.
+    /// Returns the number of pages moved.
+    fn move_file(
+        &mut self,
+        index: u64,
+        find_pages: impl Fn(&Self, &[u64], &[u64], u64) -> Result<Option<(Vec<u64>, Vec<u64>)>, Error>,
+    ) -> Result<u64, Error> {
+        let mut file = self.get_file_struct_by_index(index)?;
+        let extent_map = self.get_extent_map(&file)?;
+        let extents: Vec<std::ops::Range<u64>> = extent_map
+            .iter()
+            .flat_map(|(_, extent_page)| extent_page.extents.iter().cloned())
+            .collect();
+        let data_pages: Vec<u64> = extents
+            .iter()
+            .filter(|extent| !is_hole(extent))
+            .flat_map(|extent| extent.clone())
+            .collect();
+        let map_pages: Vec<u64> = extent_map.iter().map(|(page, _)| *page).collect();
+        let extents_per_page = self.get_extents_per_page();
+
+        let mut extra_map_pages = 0;
+        let (new_data_pages, new_extents, new_map_pages, end) = loop {
+            let Some((new_data_pages, mut new_map_pages)) =
+                find_pages(self, &data_pages, &map_pages, extra_map_pages)?
+            else {
+                return Ok(0);
+            };
+            let end = new_data_pages
+                .iter()
+                .chain(&new_map_pages)
+                .max()
+                .map_or(0, |page| page + 1);
+            if end > self.get_allocation_table_capacity() {
+                self.grow_allocation_table(end)?;
+                continue;
+            }
 
-                if entity.is_directory {
-                    self.print_tree_recursive(entity.contents, depth + 1);
+            let mut new_extents = Vec::new();
+            let mut new_pages = new_data_pages.iter();
+            for extent in &extents {
+                if is_hole(extent) {
+                    push_extent(&mut new_extents, extent.clone());
+                    continue;
+                }
+                for page_number in new_pages
+                    .by_ref()
+                    .take((extent.end - extent.start) as usize)
+                {
+                    push_extent(&mut new_extents, *page_number..(page_number + 1));
                 }
             }
-            pointer = entity.next;
+
+            let map_pages_needed = new_extents.len().div_ceil(extents_per_page);
+            if map_pages_needed > new_map_pages.len() {
+                extra_map_pages += (map_pages_needed - new_map_pages.len()) as u64;
+                continue;
+            }
+            new_map_pages.truncate(map_pages_needed);
+
+            break (new_data_pages, new_extents, new_map_pages, end);
+        };
+
+        let old_pages: BTreeSet<u64> = data_pages.iter().chain(&map_pages).copied().collect();
+        let new_pages: BTreeSet<u64> = new_data_pages
+            .iter()
+            .chain(&new_map_pages)
+            .copied()
+            .collect();
+        self.ensure_length(end)?;
+
+        //critical
+        let copies = page_runs(
+            data_pages
+                .iter()
+                .zip(&new_data_pages)
+                .filter(|(page_number, new_page_number)| page_number != new_page_number)
+                .map(|(&page_number, &new_page_number)| (page_number, new_page_number)),
+        );
+        let mut buffer = Vec::new();
+        for (source, target, length) in copies {
+            let mut copied = 0;
+            while copied < length {
+                let chunk = (length - copied).min(MOVE_CHUNK_PAGES);
+                buffer.resize((chunk * self.page_size) as usize, 0);
+                self.read_bytes((source + copied) * self.page_size, &mut buffer)?;
+                self.write_bytes((target + copied) * self.page_size, &buffer)?;
+                copied += chunk;
+            }
         }
-    }
+        //critical
 
-    fn allocate_page(&self, page_number: u64, is_allocated: bool) -> Result<(), Error> {
-        if page_number >= self.page_size * 8 {
-            return Err(Error::PageNumberTooBig);
+        self.sync_before_commit()?;
+
+        let mut modifications = Vec::new();
+        let released = old_pages.difference(&new_pages).map(|&page| (page, page));
+        for (start, _, length) in page_runs(released) {
+            modifications.push(Modification::AllcationTable(start..(start + length), false));
+        }
+        let taken = new_pages.difference(&old_pages).map(|&page| (page, page));
+        for (start, _, length) in page_runs(taken) {
+            modifications.push(Modification::AllcationTable(start..(start + length), true));
         }
+        modifications.append(&mut self.update_extent_map(
+            &extent_map,
+            &new_extents,
+            &new_map_pages,
+        ));
 
-        let mut physical_file = self.physical_file.borrow_mut();
+        file.contents = new_map_pages.first().copied().unwrap_or(0);
+        modifications.push(Modification::FileTable(index, file));
 
-        let byte_location = page_number / 8;
-        let bit_location = page_number % 8;
+        self.schedule_commit(modifications)?;
+        self.resolve_commit()?;
 
-        physical_file
-            .seek(SeekFrom::Start(
-                self.page_size * DEFAULT_ALLOCATION_TABLE_PAGE + byte_location,
-            ))
-            .unwrap();
-        let mut byte = [0; 1];
-        physical_file.read_exact(&mut byte).unwrap();
-        byte[0] = if is_allocated {
-            byte[0] | 1 << bit_location
-        } else {
-            byte[0] & !(1 << bit_location)
-        };
-        physical_file.seek(SeekFrom::Current(-1)).unwrap();
-        physical_file.write_all(&byte).unwrap();
+        Ok(new_pages.difference(&old_pages).count() as u64)
+    }
+
+    /// Gives the first `length` bytes of a file physical pages, whether they are past its size or not.
+    fn allocate_file(&mut self, index: u64, length: u64) -> Result<(), Error> {
+        let mut file = self.get_file_struct_by_index(index)?;
+        let mut extents = self.get_extents(&file)?;
+        let pages = 0..ceil_div(length, self.page_size);
+        //holes inside the file get pages that still hold whatever was there before
+        let size_pages = ceil_div(file.size, self.page_size).min(pages.end);
+        let filled_holes = hole_page_ranges(&extents, 0..size_pages);
+
+        let mut modifications = self.allocate_file_pages(&mut file, &mut extents, pages)?;
+        if modifications.is_empty() {
+            return Ok(());
+        }
+        for range in filled_holes {
+            let end = (range.end * self.page_size).min(file.size);
+            self.zero_file_data(&extents, range.start * self.page_size, end)?;
+        }
+        modifications.push(Modification::FileTable(index, file));
 
-        //println!("{:<08b} {}", byte[0], page_number);
+        self.schedule_commit(modifications)?;
+        self.resolve_commit()?;
 
         Ok(())
     }
 
-    fn allocate_page_range(
-        &self,
-        page_range: std::ops::Range<u64>,
-        is_allocated: bool,
+    /// Writes `buf` at `offset` of a file in a single commit, see [`File::write_at`].
+    fn write_file(
+        &mut self,
+        index: u64,
+        mut file: FileStruct,
+        offset: u64,
+        buf: &[u8],
     ) -> Result<(), Error> {
-        if page_range.end > self.page_size * 8 {
-            return Err(Error::PageNumberTooBig);
-        }
+        let mut extents = self.get_extents(&file)?;
+        let page_size = self.page_size;
+        let end = offset + buf.len() as u64;
+        let pages = if buf.is_empty() {
+            0..0
+        } else {
+            (offset / page_size)..ceil_div(end, page_size)
+        };
+        //pages that were holes so far come with whatever they held before
+        let head_was_hole = count_hole_pages(&extents, pages.start..(pages.start + 1)) > 0;
+        let tail_was_hole = count_hole_pages(&extents, (pages.end.max(1) - 1)..pages.end) > 0;
 
-        if page_range.is_empty() {
-            return Ok(());
+        let mut modifications = self.grow_file_pages(&mut file, &mut extents, pages.clone())?;
+
+        //critical
+        //a write past the end leaves zeros in between, whatever the pages held before
+        self.zero_file_data(&extents, file.size, offset)?;
+        if head_was_hole {
+            self.zero_file_data(&extents, pages.start * page_size, offset)?;
         }
+        if tail_was_hole {
+            self.zero_file_data(&extents, end, pages.end * page_size)?;
+        }
+        self.write_file_data(&extents, offset, buf)?;
 
-        for page_number in page_range {
-            self.allocate_page(page_number, is_allocated)?;
+        //an empty write past the end leaves the size alone, like on the host
+        if !buf.is_empty() {
+            file.size = file.size.max(end);
         }
+        file.last_write_time = Self::get_system_time();
+
+        modifications.push(Modification::FileTable(index, file));
+        self.schedule_commit(modifications)?;
+        self.resolve_commit()?;
 
         Ok(())
     }
 
-    fn is_page_allocated(&self, page_number: u64) -> Result<bool, Error> {
-        if page_number >= self.page_size {
-            return Err(Error::PageNumberTooBig);
-        }
-
-        let mut physical_file = self.physical_file.borrow_mut();
+    fn rename_entity(&mut self, from: &str, to: &str, overwrite: bool) -> Result<(), Error> {
+        self.check_writable()?;
+        let (source_parent, source_name) = self.get_parent_dir_by_path(from)?;
+        let source_index = self
+            .find_child_by_name(source_parent, source_name)?
+            .ok_or(Error::FileNotFound)?;
 
-        let byte_location = page_number / 8;
-        let bit_location = page_number % 8;
+        let (target_parent, target_name) = self.get_parent_dir_by_path(to)?;
+        if target_name.len() > FILE_NAME_SIZE {
+            return Err(Error::FileNameTooBig);
+        }
 
-        physical_file
-            .seek(SeekFrom::Start(
-                self.page_size * DEFAULT_ALLOCATION_TABLE_PAGE + byte_location,
-            ))
-            .unwrap();
-        let mut byte = [0; 1];
-        physical_file.read_exact(&mut byte).unwrap();
+        let source = self.get_file_struct_by_index(source_index)?;
+        if source.is_directory && self.is_in_subtree(source_index, target_parent)? {
+            return Err(Error::InvalidRename);
+        }
 
-        //println!("{} -> ({}, {}) -> {:b} ", page_number, byte_location, bit_location, byte[0]);
+        //changed file structs, so that one commit can touch the same entry more than once
+        let mut staged = BTreeMap::new();
+        let mut modifications = Vec::new();
 
-        Ok(byte[0] & 1 << bit_location != 0)
-    }
+        if let Some(target_index) = self.find_child_by_name(target_parent, target_name)? {
+            if target_index == source_index {
+                return Ok(());
+            }
+            if !overwrite {
+                return Err(Error::NameAlreadyInUse);
+            }
 
-    fn find_first_fitting_page_range(
-        &self,
-        number_of_pages_needed: u64,
-        reallocating_pages: std::ops::Range<u64>,
-    ) -> Option<u64> {
-        let mut i = 0;
-        let mut is_potential_range = false;
-        let mut potential_range_position = 0;
-        let mut potential_range_length = 0;
-        while i < self.page_size {
-            let is_current_page_allocated = self.is_page_allocated(i).unwrap()
-                && !(reallocating_pages.start <= i && i < reallocating_pages.end);
-            if is_potential_range {
-                if is_current_page_allocated {
-                    is_potential_range = false;
-                } else {
-                    potential_range_length += 1;
-                }
-            } else if !is_current_page_allocated {
-                is_potential_range = true;
-                potential_range_length = 1;
-                potential_range_position = i;
+            let mut target = self.get_file_struct_by_index(target_index)?;
+            if target.is_directory != source.is_directory {
+                return Err(Error::InvalidRename);
+            }
+            if target.is_directory && target.contents != 0 {
+                return Err(Error::DirectoryNotEmpty);
             }
 
-            //println!("pos: {} -> {}, {}", i, is_potential_range, potential_range_length);
-            if is_potential_range && potential_range_length == number_of_pages_needed {
-                return Some(potential_range_position);
+            self.unlink_entry(&mut staged, target_parent, target_index)?;
+            if !target.is_directory {
+                modifications = self.release_file_pages(&target)?;
             }
+            target = self.get_staged_file_struct(&staged, target_index)?;
+            target.is_active = false;
+            staged.insert(target_index, target);
+        }
 
-            i += 1;
+        self.unlink_entry(&mut staged, source_parent, source_index)?;
+        self.link_entry(&mut staged, target_parent, source_index)?;
+
+        let mut source = self.get_staged_file_struct(&staged, source_index)?;
+        source.name = target_name.to_owned();
+        staged.insert(source_index, source);
+
+        for (index, file) in staged {
+            modifications.push(Modification::FileTable(index, file));
         }
-        None
+
+        self.schedule_commit(modifications)?;
+        self.resolve_commit()?;
+
+        Ok(())
     }
 
-    fn get_number_of_pages(&self) -> u64 {
-        let mut last_allocated_page = 0;
-        for i in 0..self.page_size {
-            if self.is_page_allocated(i).unwrap() {
-                last_allocated_page = i;
-            }
+    fn get_staged_file_struct(
+        &self,
+        staged: &BTreeMap<u64, FileStruct>,
+        index: u64,
+    ) -> Result<FileStruct, Error> {
+        match staged.get(&index) {
+            Some(file) => Ok(file.clone()),
+            None => self.get_file_struct_by_index(index),
         }
-        last_allocated_page + 1
     }
 
-    fn update_file_by_index(&self, index: u64, file: FileStruct) {
-        let mut physical_file = self.physical_file.borrow_mut();
-        physical_file
-            .seek(SeekFrom::Start(self.get_file_table_index() + index))
-            .unwrap();
-        let bytes: FileBytes = file.try_into().unwrap();
-        physical_file.write_all(&bytes).unwrap();
-    }
+    /// Takes `index` out of the list of children of `parent_index`.
+    fn unlink_entry(
+        &self,
+        staged: &mut BTreeMap<u64, FileStruct>,
+        parent_index: u64,
+        index: u64,
+    ) -> Result<(), Error> {
+        let entity = self.get_staged_file_struct(staged, index)?;
+        let mut parent = self.get_staged_file_struct(staged, parent_index)?;
 
-    fn schedule_commit(&self, modifications: Vec<Modification>) {
-        let mut bytes: Vec<u8> = Vec::new();
-        let count = modifications.len() as u8;
-        for modification in modifications {
-            match modification {
-                Modification::FileTable(index, file) => {
-                    bytes.push(1);
-                    bytes.append(&mut index.to_le_bytes().to_vec());
-                    let file_bytes: FileBytes = file.try_into().unwrap();
-                    bytes.append(&mut file_bytes.to_vec());
-                }
-                Modification::AllcationTable(range, is_allocated) => {
-                    bytes.push(2);
-                    bytes.append(&mut range.start.to_le_bytes().to_vec());
-                    bytes.append(&mut range.end.to_le_bytes().to_vec());
-                    bytes.push(is_allocated as u8);
-                }
-                Modification::SystemHeader(file_table_page, file_table_size) => {
-                    bytes.push(0);
-                    bytes.append(&mut file_table_page.to_le_bytes().to_vec());
-                    bytes.append(&mut file_table_size.to_le_bytes().to_vec());
-                }
-            }
+        if parent.contents == index {
+            parent.contents = entity.next;
+            staged.insert(parent_index, parent);
+            return Ok(());
         }
 
-        let mut physical_file = self.physical_file.borrow_mut();
-        physical_file
-            .seek(SeekFrom::Start(
-                DEFAULT_SYSTEM_COMMIT_PAGE * self.page_size + 1,
-            ))
-            .unwrap();
-        physical_file.write_all(&bytes).unwrap();
-        physical_file.flush().unwrap();
-        physical_file
-            .seek(SeekFrom::Start(DEFAULT_SYSTEM_COMMIT_PAGE * self.page_size))
-            .unwrap();
-        physical_file.write_all(&[count]).unwrap();
-        physical_file.flush().unwrap();
+        let mut current_pointer = parent.contents;
+        while current_pointer != 0 {
+            let mut child_entity = self.get_staged_file_struct(staged, current_pointer)?;
+            if child_entity.next == index {
+                child_entity.next = entity.next;
+                staged.insert(current_pointer, child_entity);
+                return Ok(());
+            }
+            current_pointer = child_entity.next;
+        }
+        Ok(())
     }
 
-    fn resolve_commit(&self) {
-        let mut count = [0; 1];
+    /// Appends `index` to the end of the list of children of `parent_index`.
+    fn link_entry(
+        &self,
+        staged: &mut BTreeMap<u64, FileStruct>,
+        parent_index: u64,
+        index: u64,
+    ) -> Result<(), Error> {
+        let mut entity = self.get_staged_file_struct(staged, index)?;
+        entity.next = 0;
+        staged.insert(index, entity);
+
+        let mut parent = self.get_staged_file_struct(staged, parent_index)?;
+        if parent.contents == 0 {
+            parent.contents = index;
+            staged.insert(parent_index, parent);
+            return Ok(());
+        }
 
-        {
-            let mut physical_file = self.physical_file.borrow_mut();
-            physical_file
-                .seek(SeekFrom::Start(DEFAULT_SYSTEM_COMMIT_PAGE * self.page_size))
-                .unwrap();
-            physical_file.read_exact(&mut count).unwrap();
+        let mut current_pointer = parent.contents;
+        loop {
+            let mut child_entity = self.get_staged_file_struct(staged, current_pointer)?;
+            if child_entity.next == 0 {
+                child_entity.next = index;
+                staged.insert(current_pointer, child_entity);
+                return Ok(());
+            }
+            current_pointer = child_entity.next;
         }
+    }
+}
 
-        let mut modifications = Vec::new();
+/// Rolls back the transaction if it is still running when dropped, e.g. after an error or a panic,
+/// and lets the other threads back in.
+struct TransactionGuard<'a> {
+    internal: &'a VfsLock,
+}
 
-        for _ in 0..count[0] {
-            let mut type_byte = [0; 1];
+impl Drop for TransactionGuard<'_> {
+    fn drop(&mut self) {
+        //a panic halfway through a change poisons the lock, the rollback puts everything back anyway
+        let mut internal = self
+            .internal
+            .internal
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        //the others only get in once the rollback is done and the lock is released
+        *self
+            .internal
+            .transaction_owner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+        self.internal.transaction_ended.notify_all();
+
+        let rolled_back = internal.is_in_transaction();
+        if rolled_back {
+            internal.rollback_transaction();
+        }
+        drop(internal);
+        if rolled_back {
+            self.internal.internal.clear_poison();
+        }
+    }
+}
 
-            {
-                let mut physical_file = self.physical_file.borrow_mut();
-                physical_file.read_exact(&mut type_byte).unwrap();
-            }
-
-            match type_byte[0] {
-                0 => {
-                    let mut page_bytes = 0u64.to_le_bytes();
-                    let mut size_bytes = 0u64.to_le_bytes();
-                    {
-                        let mut physical_file = self.physical_file.borrow_mut();
-                        physical_file.read_exact(&mut page_bytes).unwrap();
-                        physical_file.read_exact(&mut size_bytes).unwrap();
-                    }
-                    modifications.push(Modification::SystemHeader(
-                        u64::from_le_bytes(page_bytes),
-                        u64::from_le_bytes(size_bytes),
-                    ));
-                }
-                1 => {
-                    let mut index_bytes = 0u64.to_le_bytes();
-                    let mut file_bytes: FileBytes = [0; FILE_STRUCT_SIZE];
-                    {
-                        let mut physical_file = self.physical_file.borrow_mut();
-                        physical_file.read_exact(&mut index_bytes).unwrap();
-                        physical_file.read_exact(&mut file_bytes).unwrap();
-                    }
-                    modifications.push(Modification::FileTable(
-                        u64::from_le_bytes(index_bytes),
-                        file_bytes.into(),
-                    ));
-                    //self.update_file_by_index(u64::from_le_bytes(index_bytes), file_bytes.into())
-                }
-                2 => {
-                    let mut start_bytes = 0u64.to_le_bytes();
-                    let mut end_bytes = 0u64.to_le_bytes();
-                    let mut is_allocated_byte = [0; 1];
-                    {
-                        let mut physical_file = self.physical_file.borrow_mut();
-                        physical_file.read_exact(&mut start_bytes).unwrap();
-                        physical_file.read_exact(&mut end_bytes).unwrap();
-                        physical_file.read_exact(&mut is_allocated_byte).unwrap();
-                    }
+/// The image shared by a [`Vfs`] and everything opened from it. Readers run side by side and writers
+/// one at a time, while a [`Vfs::transaction`] keeps every other thread out until it is done.
+struct VfsLock {
+    internal: RwLock<VfsInternal>,
+    //thread running a transaction, nobody else gets in until it ends
+    transaction_owner: Mutex<Option<ThreadId>>,
+    transaction_ended: Condvar,
+}
 
-                    modifications.push(Modification::AllcationTable(
-                        u64::from_le_bytes(start_bytes)..u64::from_le_bytes(end_bytes),
-                        is_allocated_byte[0] != 0,
-                    ));
-                    //self.allocate_page_range(u64::from_le_bytes(start_bytes)..u64::from_le_bytes(end_bytes), is_allocated_byte[0] != 0).unwrap();
-                }
-                _ => {}
-            }
+impl VfsLock {
+    fn new(internal: VfsInternal) -> VfsLock {
+        VfsLock {
+            internal: RwLock::new(internal),
+            transaction_owner: Mutex::new(None),
+            transaction_ended: Condvar::new(),
         }
+    }
 
-        for modification in modifications {
-            match modification {
-                Modification::FileTable(index, file) => self.update_file_by_index(index, file),
-                Modification::AllcationTable(range, is_allocated) => {
-                    self.allocate_page_range(range, is_allocated).unwrap()
-                }
-                Modification::SystemHeader(file_table_page, file_table_size) => {
-                    self.update_header(file_table_page, file_table_size)
-                }
-            }
+    /// Waits until no other thread is running a transaction. Until the guard is dropped none can start one.
+    fn wait_for_transaction(&self) -> MutexGuard<'_, Option<ThreadId>> {
+        let current = thread::current().id();
+        let mut owner = self.transaction_owner.lock().unwrap();
+        while owner.is_some_and(|owner| owner != current) {
+            owner = self.transaction_ended.wait(owner).unwrap();
         }
+        owner
+    }
 
-        {
-            let number_of_pages = self.get_number_of_pages();
-            let mut physical_file = self.physical_file.borrow_mut();
-            physical_file
-                .set_len(number_of_pages * self.page_size)
-                .unwrap();
-            physical_file
-                .seek(SeekFrom::Start(DEFAULT_SYSTEM_COMMIT_PAGE * self.page_size))
-                .unwrap();
-            physical_file.write_all(&[0]).unwrap();
-            physical_file.flush().unwrap();
+    fn read(&self) -> RwLockReadGuard<'_, VfsInternal> {
+        let _owner = self.wait_for_transaction();
+        if self.internal.is_poisoned() {
+            drop(self.recover());
         }
+        self.internal.read().unwrap_or_else(PoisonError::into_inner)
     }
 
-    fn update_header(&self, file_table_page: u64, file_table_size: u64) {
-        let mut physical_file = self.physical_file.borrow_mut();
-        physical_file.seek(SeekFrom::Start(16)).unwrap();
-        physical_file
-            .write_all(&file_table_page.to_le_bytes())
-            .unwrap();
-        physical_file
-            .write_all(&file_table_size.to_le_bytes())
-            .unwrap();
+    fn write(&self) -> RwLockWriteGuard<'_, VfsInternal> {
+        let _owner = self.wait_for_transaction();
+        self.recover()
+    }
+
+    /// Takes the write guard, first cleaning up after a thread that panicked while it held it.
+    fn recover(&self) -> RwLockWriteGuard<'_, VfsInternal> {
+        let mut internal = self
+            .internal
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        if self.internal.is_poisoned() {
+            if internal.is_in_transaction() {
+                internal.rollback_transaction();
+            }
+            //a commit that was already scheduled goes through, like after a crash.
+            //if that fails the journal is still there for the next open
+            if !internal.read_only {
+                internal.resolve_commit().ok();
+            }
+            self.internal.clear_poison();
+        }
+        internal
     }
+}
 
-    fn delete_recursive(&self, prev_index: u64, index: u64, is_parent_dir: bool) {
-        let entity = self.get_file_struct_by_index(index);
+#[derive(Debug, PartialEq)]
+enum Modification {
+    SystemHeader(u64, u64),
+    FileTable(u64, FileStruct),
+    AllcationTable(std::ops::Range<u64>, bool),
+    AllocationTableHeader(u64, u64),
+    ExtentMap(u64, ExtentPage),
+    //first staging page and number of page images staged by a transaction
+    Pages(u64, u64),
+}
 
-        if entity.is_directory {
-            let mut previous_pointer = index;
-            let mut current_pointer = entity.contents;
-            let previous_pointer_copy = previous_pointer;
+impl From<Modification> for Vec<u8> {
+    fn from(value: Modification) -> Self {
+        let mut bytes = Vec::new();
+        match value {
+            Modification::SystemHeader(file_table_page, file_table_size) => {
+                bytes.push(0);
+                bytes.extend_from_slice(&file_table_page.to_le_bytes());
+                bytes.extend_from_slice(&file_table_size.to_le_bytes());
+            }
+            Modification::FileTable(index, file) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&index.to_le_bytes());
+                let file_bytes: FileBytes = file.try_into().unwrap();
+                bytes.extend_from_slice(&file_bytes);
+            }
+            Modification::AllcationTable(range, is_allocated) => {
+                bytes.push(2);
+                bytes.extend_from_slice(&range.start.to_le_bytes());
+                bytes.extend_from_slice(&range.end.to_le_bytes());
+                bytes.push(is_allocated as u8);
+            }
+            Modification::AllocationTableHeader(allocation_table_page, allocation_table_size) => {
+                bytes.push(3);
+                bytes.extend_from_slice(&allocation_table_page.to_le_bytes());
+                bytes.extend_from_slice(&allocation_table_size.to_le_bytes());
+            }
+            Modification::ExtentMap(page_number, extent_page) => {
+                bytes.push(4);
+                bytes.extend_from_slice(&page_number.to_le_bytes());
+                bytes.append(&mut extent_page.into());
+            }
+            Modification::Pages(staging_page, number_of_pages) => {
+                bytes.push(5);
+                bytes.extend_from_slice(&staging_page.to_le_bytes());
+                bytes.extend_from_slice(&number_of_pages.to_le_bytes());
+            }
+        }
+        bytes
+    }
+}
 
-            while current_pointer != 0 {
-                let child_entity = self.get_file_struct_by_index(current_pointer);
+impl TryFrom<&[u8]> for Modification {
+    type Error = Error;
 
-                self.delete_recursive(
-                    previous_pointer,
-                    current_pointer,
-                    previous_pointer == previous_pointer_copy,
-                );
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let read_u64 = |offset: usize| -> Result<u64, Error> {
+            let bytes = value
+                .get(offset..(offset + 8))
+                .ok_or(Error::IncompleteRead)?;
+            Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+        };
+        let expect_length = |length: usize| {
+            if value.len() == length {
+                Ok(())
+            } else {
+                Err(Error::IncompleteRead)
+            }
+        };
 
-                previous_pointer = current_pointer;
-                current_pointer = child_entity.next;
+        match value.first() {
+            Some(0) => {
+                expect_length(17)?;
+                Ok(Modification::SystemHeader(read_u64(1)?, read_u64(9)?))
             }
-            self.delete_single_dir(prev_index, index, is_parent_dir);
-        } else {
-            self.delete_single_file(prev_index, index, is_parent_dir);
+            Some(1) => {
+                expect_length(9 + FILE_STRUCT_SIZE)?;
+                let file_bytes: FileBytes = value[9..].try_into().unwrap();
+                Ok(Modification::FileTable(read_u64(1)?, file_bytes.into()))
+            }
+            Some(2) => {
+                expect_length(18)?;
+                Ok(Modification::AllcationTable(
+                    read_u64(1)?..read_u64(9)?,
+                    value[17] != 0,
+                ))
+            }
+            Some(3) => {
+                expect_length(17)?;
+                Ok(Modification::AllocationTableHeader(
+                    read_u64(1)?,
+                    read_u64(9)?,
+                ))
+            }
+            Some(4) => {
+                let count = read_u64(17)?;
+                expect_length(25 + count as usize * EXTENT_SIZE)?;
+                Ok(Modification::ExtentMap(
+                    read_u64(1)?,
+                    ExtentPage::from(&value[9..]),
+                ))
+            }
+            Some(5) => {
+                expect_length(17)?;
+                Ok(Modification::Pages(read_u64(1)?, read_u64(9)?))
+            }
+            _ => Err(Error::IncompleteRead),
         }
     }
+}
 
-    fn delete_single_dir(&self, prev_index: u64, index: u64, is_parent_dir: bool) {
-        let mut dir = self.get_file_struct_by_index(index);
-        dir.is_active = false;
-        //if dir.is_root { println!("found root");}
-        let mut prev = self.get_file_struct_by_index(prev_index);
-        if is_parent_dir {
-            prev.contents = dir.next;
-        } else {
-            prev.next = dir.next;
+/// Parses the records of a journal, `None` when any of them doesn't add up.
+fn parse_journal_records(bytes: &[u8], count: u64, sequence: u64) -> Option<Vec<Modification>> {
+    let mut modifications = Vec::new();
+    let mut position = 0;
+    for _ in 0..count {
+        let record_length =
+            u32::from_le_bytes(bytes.get(position..(position + 4))?.try_into().unwrap()) as usize;
+        position += 4;
+        let record = bytes.get(position..(position + record_length))?;
+        position += record_length;
+        let checksum = u32::from_le_bytes(bytes.get(position..(position + 4))?.try_into().unwrap());
+        position += 4;
+
+        if record_checksum(sequence, record) != checksum {
+            return None;
         }
+        modifications.push(Modification::try_from(record).ok()?);
+    }
 
-        let modifications = vec![
-            Modification::FileTable(index, dir),
-            Modification::FileTable(prev_index, prev),
-        ];
-        self.schedule_commit(modifications);
-        self.resolve_commit();
+    if position != bytes.len() {
+        return None;
     }
 
-    fn delete_single_file(&self, prev_index: u64, index: u64, is_parent_dir: bool) {
-        let mut file: FileStruct = self.get_file_struct_by_index(index);
-        file.is_active = false;
-        let mut prev = self.get_file_struct_by_index(prev_index);
-        if is_parent_dir {
-            prev.contents = file.next;
-        } else {
-            prev.next = file.next;
-        }
+    Some(modifications)
+}
 
-        let modifications = vec![
-            Modification::AllcationTable(
-                file.contents..(file.contents + ceil_div(file.size, self.page_size)),
-                false,
-            ),
-            Modification::FileTable(index, file),
-            Modification::FileTable(prev_index, prev),
-        ];
-        self.schedule_commit(modifications);
-        self.resolve_commit();
-    }
+fn record_checksum(sequence: u64, record: &[u8]) -> u32 {
+    let mut bytes = sequence.to_le_bytes().to_vec();
+    bytes.extend_from_slice(record);
+    crc32(&bytes)
 }
 
-#[derive(Debug)]
-enum Modification {
-    SystemHeader(u64, u64),
-    FileTable(u64, FileStruct),
-    AllcationTable(std::ops::Range<u64>, bool),
+/// CRC-32 (IEEE), used to check journal records.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
 }
 
 #[derive(Debug, PartialEq)]
@@ -1456,6 +5975,12 @@ pub enum Error {
     FileNotFound,
     NameAlreadyInUse,
     PageNumberTooBig,
+    InvalidOptions,
+    NotAVfsImage,
+    UnsupportedVersion(u32),
+    InvalidRename,
+    DirectoryNotEmpty,
+    ReadOnly,
 }
 
 impl From<std::io::Error> for Error {
@@ -1464,6 +5989,159 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<Error> for std::io::Error {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::IO(kind) => std::io::Error::from(kind),
+            other => std::io::Error::other(format!("{:?}", other)),
+        }
+    }
+}
+
+const EXTENT_SIZE: usize = 2 * size_of::<u64>();
+//pages copied at once when a file is moved
+const MOVE_CHUNK_PAGES: u64 = 64;
+
+/// One page of the extent map of a file. Each extent is a run of physical pages or a hole, in file order.
+#[derive(Debug, Clone, PartialEq)]
+struct ExtentPage {
+    next: u64,
+    extents: Vec<std::ops::Range<u64>>,
+}
+
+impl From<ExtentPage> for Vec<u8> {
+    fn from(value: ExtentPage) -> Self {
+        let mut bytes =
+            Vec::with_capacity(2 * size_of::<u64>() + value.extents.len() * EXTENT_SIZE);
+        bytes.extend_from_slice(&value.next.to_le_bytes());
+        bytes.extend_from_slice(&(value.extents.len() as u64).to_le_bytes());
+        for extent in value.extents {
+            bytes.extend_from_slice(&extent.start.to_le_bytes());
+            bytes.extend_from_slice(&extent.end.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+impl From<&[u8]> for ExtentPage {
+    fn from(value: &[u8]) -> Self {
+        let read_u64 =
+            |offset: usize| u64::from_le_bytes(value[offset..(offset + 8)].try_into().unwrap());
+
+        let next = read_u64(0);
+        let count = (read_u64(8) as usize).min((value.len() - 16) / EXTENT_SIZE);
+        let extents = (0..count)
+            .map(|i| read_u64(16 + i * EXTENT_SIZE)..read_u64(24 + i * EXTENT_SIZE))
+            .collect();
+
+        ExtentPage { next, extents }
+    }
+}
+
+/// An extent starting at page 0 is a hole: that many pages of the file were never written and read
+/// as zeros. Page 0 always holds the header, so no data can start there.
+fn is_hole(extent: &std::ops::Range<u64>) -> bool {
+    extent.start == 0
+}
+
+fn hole(number_of_pages: u64) -> std::ops::Range<u64> {
+    0..number_of_pages
+}
+
+fn push_extent(extents: &mut Vec<std::ops::Range<u64>>, run: std::ops::Range<u64>) {
+    if run.is_empty() {
+        return;
+    }
+    match extents.last_mut() {
+        Some(last) if is_hole(last) && is_hole(&run) => last.end += run.end,
+        Some(last) if !is_hole(last) && last.end == run.start => last.end = run.end,
+        _ => extents.push(run),
+    }
+}
+
+/// Joins pairs of pages that both keep going up by one into runs, as `(start, new start, length)`.
+fn page_runs(pairs: impl IntoIterator<Item = (u64, u64)>) -> Vec<(u64, u64, u64)> {
+    let mut runs: Vec<(u64, u64, u64)> = Vec::new();
+    for (page_number, new_page_number) in pairs {
+        match runs.last_mut() {
+            Some((start, new_start, length))
+                if *start + *length == page_number && *new_start + *length == new_page_number =>
+            {
+                *length += 1
+            }
+            _ => runs.push((page_number, new_page_number, 1)),
+        }
+    }
+    runs
+}
+
+/// Number of pages in `pages` that a file doesn't have, because they are holes or past its last page.
+fn count_hole_pages(extents: &[std::ops::Range<u64>], pages: std::ops::Range<u64>) -> u64 {
+    let mut logical_page = 0;
+    let mut count = 0;
+    for extent in extents {
+        let extent_end = logical_page + (extent.end - extent.start);
+        if is_hole(extent) {
+            count += pages
+                .end
+                .min(extent_end)
+                .saturating_sub(pages.start.max(logical_page));
+        }
+        logical_page = extent_end;
+    }
+    count + pages.end.saturating_sub(pages.start.max(logical_page))
+}
+
+/// The runs of pages in `pages` that a file doesn't have, see [`count_hole_pages`].
+fn hole_page_ranges(
+    extents: &[std::ops::Range<u64>],
+    pages: std::ops::Range<u64>,
+) -> Vec<std::ops::Range<u64>> {
+    let mut ranges = Vec::new();
+    let mut logical_page = 0;
+    for extent in extents {
+        let extent_end = logical_page + (extent.end - extent.start);
+        if is_hole(extent) {
+            ranges.push(pages.start.max(logical_page)..pages.end.min(extent_end));
+        }
+        logical_page = extent_end;
+    }
+    ranges.push(pages.start.max(logical_page)..pages.end);
+    ranges.retain(|range| range.start < range.end);
+    ranges
+}
+
+/// Hands the physical pages of `runs`, in order, to the holes of `extents` that lie in `pages`,
+/// including the ones past the last page.
+fn fill_holes(
+    extents: &[std::ops::Range<u64>],
+    pages: std::ops::Range<u64>,
+    runs: Vec<std::ops::Range<u64>>,
+) -> Vec<std::ops::Range<u64>> {
+    let mut supply = runs.into_iter().flatten();
+    let number_of_pages: u64 = extents.iter().map(|extent| extent.end - extent.start).sum();
+    let past_the_end = hole(pages.end.saturating_sub(number_of_pages));
+
+    let mut new_extents = Vec::new();
+    let mut logical_page = 0;
+    for extent in extents.iter().cloned().chain(std::iter::once(past_the_end)) {
+        let length = extent.end - extent.start;
+        if is_hole(&extent) {
+            let fill_start = pages.start.clamp(logical_page, logical_page + length);
+            let fill_end = pages.end.clamp(logical_page, logical_page + length);
+            push_extent(&mut new_extents, hole(fill_start - logical_page));
+            for page in supply.by_ref().take((fill_end - fill_start) as usize) {
+                push_extent(&mut new_extents, page..(page + 1));
+            }
+            push_extent(&mut new_extents, hole(logical_page + length - fill_end));
+        } else {
+            push_extent(&mut new_extents, extent);
+        }
+        logical_page += length;
+    }
+    new_extents
+}
+
 const FILE_NAME_SIZE: usize = 128;
 const FILE_STRUCT_SIZE: usize = 1 + 5 * size_of::<u64>() + FILE_NAME_SIZE;
 type FileBytes = [u8; FILE_STRUCT_SIZE];
@@ -1473,7 +6151,7 @@ struct FileStruct {
     is_root: bool,
     is_active: bool,
     is_directory: bool,
-    contents: u64,
+    contents: u64, //first child for directories, first extent map page for files
     next: u64,
     size: u64, //number of bytes of actual file (divide by page_size to get number of pages)
     creation_time: u64,
@@ -1543,7 +6221,7 @@ impl From<[u8; FILE_STRUCT_SIZE]> for FileStruct {
             is_root: (flags[0] & 1u8) != 0,
             is_active: (flags[0] & (1u8 << 1)) != 0,
             is_directory: (flags[0] & (1u8 << 2)) != 0,
-            name: String::from_utf8(trimmed_name).unwrap(),
+            name: String::from_utf8_lossy(&trimmed_name).into_owned(),
             contents: u64::from_le_bytes(contents),
             next: u64::from_le_bytes(next),
             size: u64::from_le_bytes(size),
@@ -1558,187 +6236,310 @@ pub struct File {
     path: String,
     file_index: u64,
     cursor: u64,
-    internal: Weak<RefCell<VfsInternal>>,
+    internal: Weak<VfsLock>,
+    readable: bool,
+    writable: bool,
+    append: bool,
+}
+
+/// Builder for opening files inside an image, see [`Vfs::options`].
+#[derive(Clone)]
+pub struct OpenOptions<'a> {
+    vfs: &'a Vfs,
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+}
+
+impl OpenOptions<'_> {
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    /// Every write goes to the current end of the file, whatever the cursor says. Implies `write`.
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    /// Empties the file when it is opened. Needs `write` and cannot be combined with `append`.
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    /// Creates the file if it does not exist yet.
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    /// Creates the file, failing with [`Error::NameAlreadyInUse`] if it already exists.
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.create_new = create_new;
+        self
+    }
+
+    pub fn open(&self, path: &str) -> Result<File, Error> {
+        let writable = self.write || self.append;
+        let needs_write = self.create || self.create_new || self.truncate;
+        if !writable && (!self.read || needs_write) || self.truncate && self.append {
+            return Err(Error::InvalidOptions);
+        }
+        if writable {
+            self.vfs.internal.read().check_writable()?;
+        }
+
+        if !needs_write {
+            let mut file = self.vfs.open_file(path)?;
+            file.readable = self.read;
+            file.writable = writable;
+            file.append = self.append;
+            return Ok(file);
+        }
+
+        //looking the file up and creating or truncating it happen under one lock, in one commit
+        let file_index = {
+            let mut internal = self.vfs.internal.write();
+            match internal.get_file_struct_by_path(path) {
+                Ok(_) if self.create_new => return Err(Error::NameAlreadyInUse),
+                Ok((_, file)) if file.is_directory => return Err(Error::FileNotFound),
+                Ok((index, _)) => {
+                    if self.truncate {
+                        internal.set_file_len(index, 0)?;
+                    }
+                    index
+                }
+                Err(_) if self.create || self.create_new => internal.create_entity(path, false)?,
+                Err(_) => return Err(Error::FileNotFound),
+            }
+        };
+
+        Ok(File {
+            internal: Arc::downgrade(&self.vfs.internal),
+            file_index,
+            cursor: 0,
+            path: path.to_owned(),
+            readable: self.read,
+            writable,
+            append: self.append,
+        })
+    }
 }
 
 #[derive(Debug)]
 pub struct Metadata {
     pub size: u64,
+    /// Bytes the file has pages for, it can grow up to this without allocating.
+    /// Holes don't count, spare pages reserved past `size` do.
+    pub capacity: u64,
     pub last_write_time: u64,
     pub creation_time: u64,
 }
 
 impl File {
+    #[cfg(test)]
     fn get_metadata(&self) -> FileStruct {
         let upgrade = self.internal.upgrade().unwrap();
-        let internal = upgrade.borrow_mut();
-        internal.get_file_struct_by_index(self.file_index)
+        let internal = upgrade.read();
+        internal.get_file_struct_by_index(self.file_index).unwrap()
     }
 
     pub fn metadata(&self) -> Result<Metadata, Error> {
-        {
+        let (all_metadata, capacity) = {
             let upgrade = self.internal.upgrade();
             if upgrade.is_none() {
                 return Err(Error::FileNotFound);
             }
             let upgrade = upgrade.unwrap();
-            let internal = upgrade.borrow_mut();
+            let internal = upgrade.read();
             if internal.get_file_struct_by_path(&self.path).is_err() {
                 return Err(Error::FileNotFound);
             }
-        }
+            let file = internal.get_file_struct_by_index(self.file_index)?;
+            let data_pages: u64 = internal
+                .get_extents(&file)?
+                .iter()
+                .filter(|extent| !is_hole(extent))
+                .map(|extent| extent.end - extent.start)
+                .sum();
+            (file, data_pages * internal.page_size)
+        };
 
-        let all_metadata = self.get_metadata();
         Ok(Metadata {
             size: all_metadata.size,
+            capacity,
             last_write_time: all_metadata.last_write_time,
             creation_time: all_metadata.creation_time,
         })
     }
-}
 
-impl Write for File {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        {
-            let upgrade = self.internal.upgrade();
-            if upgrade.is_none() {
-                return Err(std::io::Error::from(std::io::ErrorKind::NotFound));
-            }
-            let upgrade = upgrade.unwrap();
-            let internal = upgrade.borrow_mut();
-            if internal.get_file_struct_by_path(&self.path).is_err() {
-                return Err(std::io::Error::from(std::io::ErrorKind::NotFound));
-            }
+    /// Truncates or extends the file to `size` bytes, extending fills it with zeros.
+    /// The cursor is left where it is.
+    pub fn set_len(&self, size: u64) -> Result<(), Error> {
+        if !self.writable {
+            return Err(Error::IO(std::io::ErrorKind::PermissionDenied));
         }
 
-        let mut metadata = self.get_metadata();
-        let upgrade = self.internal.upgrade().unwrap();
-        let internal = upgrade.borrow_mut();
+        let upgrade = self.internal.upgrade().ok_or(Error::FileNotFound)?;
+        let mut internal = upgrade.write();
         if internal.get_file_struct_by_path(&self.path).is_err() {
-            return Err(std::io::Error::from(std::io::ErrorKind::NotFound));
+            return Err(Error::FileNotFound);
         }
 
-        let number_of_pages_needed = ceil_div(self.cursor + buf.len() as u64, internal.page_size);
-        let number_of_current_pages = ceil_div(metadata.size, internal.page_size);
-        let overflow_condition = number_of_current_pages < number_of_pages_needed;
+        internal.set_file_len(self.file_index, size)
+    }
 
-        // println!(
-        //     "{} {} {} {} {}",
-        //     overflow_condition,
-        //     metadata.size,
-        //     internal.page_size,
-        //     number_of_current_pages,
-        //     number_of_pages_needed
-        // );
+    /// Reserves pages for the first `length` bytes of the file up front, like `fallocate`, so writing
+    /// them later never has to allocate. The size of the file stays as it is, and making the file
+    /// shorter with [`File::set_len`] gives the reserved pages past the new end back.
+    pub fn allocate(&self, length: u64) -> Result<(), Error> {
+        if !self.writable {
+            return Err(Error::IO(std::io::ErrorKind::PermissionDenied));
+        }
 
-        if overflow_condition {
-            let temp = internal.find_first_fitting_page_range(
-                number_of_pages_needed,
-                metadata.contents..(metadata.contents + number_of_current_pages),
-            );
-            if temp.is_none() {
-                return Err(std::io::Error::from(std::io::ErrorKind::OutOfMemory));
-            }
-            let contents_location = temp.unwrap();
+        let upgrade = self.internal.upgrade().ok_or(Error::FileNotFound)?;
+        let mut internal = upgrade.write();
+        if internal.get_file_struct_by_path(&self.path).is_err() {
+            return Err(Error::FileNotFound);
+        }
 
-            //critical
-            let mut modifications = Vec::new();
+        internal.allocate_file(self.file_index, length)
+    }
 
-            modifications.push(Modification::AllcationTable(
-                metadata.contents..(metadata.contents + number_of_current_pages),
-                false,
-            ));
-            modifications.push(Modification::AllcationTable(
-                contents_location..(contents_location + number_of_pages_needed),
-                true,
-            ));
+    /// Reads from `offset` without moving the cursor, like [`std::os::unix::fs::FileExt::read_at`].
+    /// Returns 0 at or past the end of the file.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        self.read_from(buf, offset)
+    }
 
-            // internal
-            //     .allocate_page_range(
-            //         metadata.contents..(metadata.contents + number_of_current_pages),
-            //         false,
-            //     )
-            //     .unwrap();
-            // internal
-            //     .allocate_page_range(
-            //         contents_location..(contents_location + number_of_pages_needed),
-            //         true,
-            //     )
-            //     .unwrap();
-
-            let mut last_page = internal.get_number_of_pages();
-            if metadata.contents <= last_page
-                && last_page < metadata.contents + number_of_current_pages
-            {
-                last_page = metadata.contents - 1;
-            }
-            if last_page < contents_location + number_of_pages_needed - 1 {
-                last_page = contents_location + number_of_pages_needed - 1;
-            }
-            let vfs_page_total = last_page + 1;
-            // println!("{}", vfs_page_total);
+    /// Fills all of `buf` from `offset` without moving the cursor, failing with
+    /// [`std::io::ErrorKind::UnexpectedEof`] if the file ends before that.
+    pub fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        if self.read_from(buf, offset)? < buf.len() {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+        }
+        Ok(())
+    }
 
-            //println!("{} {} {} {}", metadata.contents, number_of_current_pages, contents_location, number_of_pages_needed);
+    /// Writes all of `buf` at `offset` without moving the cursor, like
+    /// [`std::os::unix::fs::FileExt::write_at`]. A file opened for appending is written at its end instead.
+    pub fn write_at(&self, buf: &[u8], offset: u64) -> std::io::Result<usize> {
+        let offset = if self.append { None } else { Some(offset) };
+        self.write_to(buf, offset)?;
+        Ok(buf.len())
+    }
 
-            {
-                let mut physical_file = internal.physical_file.borrow_mut();
-                physical_file.set_len(vfs_page_total * internal.page_size)?;
-
-                if metadata.size > 0 {
-                    let mut contents_buffer: Vec<u8> = Vec::new();
-                    contents_buffer.resize(metadata.size as usize, 0);
-                    physical_file
-                        .seek(SeekFrom::Start(metadata.contents * internal.page_size))
-                        .unwrap();
-                    physical_file.read_exact(&mut contents_buffer).unwrap();
+    fn read_from(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        if !self.readable {
+            return Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        }
 
-                    physical_file
-                        .seek(SeekFrom::Start(contents_location * internal.page_size))
-                        .unwrap();
-                    physical_file.write_all(&contents_buffer).unwrap();
-                }
-            }
+        let upgrade = self.internal.upgrade();
+        if upgrade.is_none() {
+            return Err(std::io::Error::from(std::io::ErrorKind::NotFound));
+        }
+        let upgrade = upgrade.unwrap();
+        let internal = upgrade.read();
+        if internal.get_file_struct_by_path(&self.path).is_err() {
+            return Err(std::io::Error::from(std::io::ErrorKind::NotFound));
+        }
+        let metadata = internal.get_file_struct_by_index(self.file_index)?;
+        let readable_length = buf.len().min(metadata.size.saturating_sub(offset) as usize);
 
-            //critical
+        if readable_length == 0 {
+            return Ok(0);
+        }
 
-            metadata.contents = contents_location;
+        let extents = internal.get_extents(&metadata)?;
+        internal.read_file_data(&extents, offset, &mut buf[..readable_length])?;
 
-            modifications.push(Modification::FileTable(self.file_index, metadata.clone()));
-            //internal.update_file_by_index(self.file_index, metadata.clone());
+        Ok(readable_length)
+    }
 
-            internal.schedule_commit(modifications);
-            internal.resolve_commit();
+    /// Writes `buf` at `offset`, or at the end of the file if there is none, in a single commit.
+    /// Returns where the write ended.
+    fn write_to(&self, buf: &[u8], offset: Option<u64>) -> std::io::Result<u64> {
+        if !self.writable {
+            return Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
         }
 
-        {
-            let mut physical_file = internal.physical_file.borrow_mut();
-            physical_file
-                .seek(SeekFrom::Start(
-                    metadata.contents * internal.page_size + self.cursor,
-                ))
-                .unwrap();
-            physical_file.write_all(buf).unwrap();
+        let upgrade = self.internal.upgrade();
+        if upgrade.is_none() {
+            return Err(std::io::Error::from(std::io::ErrorKind::NotFound));
+        }
+        let upgrade = upgrade.unwrap();
+        //held until the write is committed, so no other thread sees it halfway
+        let mut internal = upgrade.write();
+        if internal.get_file_struct_by_path(&self.path).is_err() {
+            return Err(std::io::Error::from(std::io::ErrorKind::NotFound));
         }
+        let metadata = internal.get_file_struct_by_index(self.file_index)?;
 
-        //critical
-        metadata.size = metadata.size.max(self.cursor + buf.len() as u64);
-        metadata.last_write_time = VfsInternal::get_system_time();
-        //internal.update_file_by_index(self.file_index, metadata.clone());
+        let offset = offset.unwrap_or(metadata.size);
+        if offset.checked_add(buf.len() as u64).is_none() {
+            return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput));
+        }
+        if buf.is_empty() {
+            return Ok(offset);
+        }
+
+        //bytes that are already there get overwritten in place, which the journal alone can't undo
+        let overwrites = offset < metadata.size && !internal.is_in_transaction();
+        if overwrites {
+            internal.begin_transaction()?;
+        }
+
+        let written = internal.write_file(self.file_index, metadata, offset, buf);
+        if overwrites {
+            match written {
+                Ok(()) => internal.commit_transaction()?,
+                Err(_) => internal.rollback_transaction(),
+            }
+        }
+        written?;
 
-        let modifications = vec![Modification::FileTable(self.file_index, metadata)];
-        internal.schedule_commit(modifications);
-        internal.resolve_commit();
+        Ok(offset + buf.len() as u64)
+    }
+
+    /// Makes sure the contents and metadata of the file are on disk, by syncing the whole image.
+    pub fn sync_all(&self) -> Result<(), Error> {
+        let upgrade = self.internal.upgrade().ok_or(Error::FileNotFound)?;
+        let internal = upgrade.read();
+        internal.storage.write().unwrap().sync_all()?;
+        Ok(())
+    }
 
-        self.cursor += buf.len() as u64;
+    /// Like [`File::sync_all`], but leaves out host file metadata that isn't needed to read the image back.
+    pub fn sync_data(&self) -> Result<(), Error> {
+        let upgrade = self.internal.upgrade().ok_or(Error::FileNotFound)?;
+        let internal = upgrade.read();
+        internal.storage.write().unwrap().sync_data()?;
+        Ok(())
+    }
+}
 
+impl Write for File {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let offset = if self.append { None } else { Some(self.cursor) };
+        self.cursor = self.write_to(buf, offset)?;
         Ok(buf.len())
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
         let upgrade = self.internal.upgrade().unwrap();
-        let internal = upgrade.borrow_mut();
-        let mut physical_file = internal.physical_file.borrow_mut();
-        physical_file.flush()
+        let internal = upgrade.read();
+        let mut storage = internal.storage.write().unwrap();
+        storage.flush()
     }
 }
 
@@ -1746,78 +6547,42 @@ impl Write for File {
 
 impl Read for File {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        {
-            let upgrade = self.internal.upgrade();
-            if upgrade.is_none() {
-                return Err(std::io::Error::from(std::io::ErrorKind::NotFound));
-            }
-            let upgrade = upgrade.unwrap();
-            let internal = upgrade.borrow_mut();
-            if internal.get_file_struct_by_path(&self.path).is_err() {
-                return Err(std::io::Error::from(std::io::ErrorKind::NotFound));
-            }
-        }
-        let metadata = self.get_metadata();
-        let readable_length = buf.len().min((metadata.size - self.cursor) as usize);
-
-        if readable_length == 0 {
-            return Ok(0);
-        }
-
-        let upgrade = self.internal.upgrade().unwrap();
-        let internal = upgrade.borrow_mut();
-        let mut physical_file = internal.physical_file.borrow_mut();
-
-        physical_file
-            .seek(SeekFrom::Start(
-                metadata.contents * internal.page_size + self.cursor,
-            ))
-            .unwrap();
-        let mut bytes = Vec::new();
-        bytes.resize(readable_length, 0);
-        physical_file.read_exact(&mut bytes).unwrap();
-
-        for (i, &byte) in bytes.iter().enumerate() {
-            buf[i] = byte;
-        }
-
-        self.cursor += readable_length as u64;
-
-        Ok(readable_length)
+        let read = self.read_from(buf, self.cursor)?;
+        self.cursor += read as u64;
+        Ok(read)
     }
 }
 
 impl Seek for File {
     fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
-        {
+        let metadata = {
             let upgrade = self.internal.upgrade();
             if upgrade.is_none() {
                 return Err(std::io::Error::from(std::io::ErrorKind::NotFound));
             }
             let upgrade = upgrade.unwrap();
-            let internal = upgrade.borrow_mut();
+            let internal = upgrade.read();
             if internal.get_file_struct_by_path(&self.path).is_err() {
                 return Err(std::io::Error::from(std::io::ErrorKind::NotFound));
             }
-        }
-        let metadata = self.get_metadata();
-        match pos {
-            SeekFrom::Start(x) if x < metadata.size => self.cursor = x,
-            SeekFrom::Current(x)
-                if 0 <= self.cursor as i128 + x as i128
-                    && (self.cursor as i128 + x as i128) < metadata.size as i128 =>
-            {
-                self.cursor = (self.cursor as i128 + x as i128) as u64
-            }
-            SeekFrom::End(x) => self.cursor = (metadata.size as i128 + x as i128) as u64,
-            _ => return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput)),
+            internal.get_file_struct_by_index(self.file_index)?
+        };
+        //like std, any position past the end is fine, the gap is filled in by the next write
+        let position = match pos {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::Current(x) => self.cursor.checked_add_signed(x),
+            SeekFrom::End(x) => metadata.size.checked_add_signed(x),
+        };
+        match position {
+            Some(position) => self.cursor = position,
+            None => return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput)),
         }
         Ok(self.cursor)
     }
 }
 
 fn ceil_div(a: u64, b: u64) -> u64 {
-    if a % b == 0 {
+    if a.is_multiple_of(b) {
         a / b
     } else {
         a / b + 1
@@ -1829,7 +6594,7 @@ pub struct DirIterator {
     path: String,
     entry_names: Vec<String>,
     cursor: usize,
-    internal: Weak<RefCell<VfsInternal>>,
+    internal: Weak<VfsLock>,
 }
 
 #[derive(Debug)]
@@ -1851,7 +6616,7 @@ impl Iterator for DirIterator {
             let upgrade = self.internal.upgrade();
             upgrade.as_ref()?;
             let upgrade = upgrade.unwrap();
-            let internal = upgrade.borrow_mut();
+            let internal = upgrade.read();
             if internal.get_file_struct_by_path(&self.path).is_err() {
                 return None;
             }
@@ -1863,7 +6628,7 @@ impl Iterator for DirIterator {
         self.cursor += 1;
 
         let upgrade = self.internal.upgrade().unwrap();
-        let internal = upgrade.borrow();
+        let internal = upgrade.read();
 
         let entry = internal.get_file_struct_by_path(&path);
 